@@ -1,122 +1,3670 @@
-use ic_asset_certification::{Asset, AssetConfig, AssetEncoding, AssetFallbackConfig, AssetRouter};
-use ic_cdk::api::{data_certificate, set_certified_data};
+use brotli::enc::BrotliEncoderParams;
+use chrono::DateTime;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use globset::Glob;
+use ic_asset_certification::{
+    Asset, AssetCertificationError, AssetConfig, AssetEncoding, AssetFallbackConfig, AssetMap,
+    AssetRedirectKind, AssetRouter, ASSET_CHUNK_SIZE,
+};
+use ic_http_certification::utils::add_v2_certificate_header;
 use ic_http_certification::{
-    HeaderField, HttpCertificationTree, HttpRequest, HttpResponse, StatusCode,
+    response_hash, DefaultCelBuilder, DefaultFullCelExpression, DefaultResponseCertification,
+    HeaderField, HttpCertification, HttpCertificationPath, HttpCertificationTree,
+    HttpCertificationTreeEntry, HttpRequest, HttpResponse, Method, StatusCode,
+    CERTIFICATE_EXPRESSION_HEADER_NAME,
 };
-use include_dir::{include_dir, Dir};
+use include_dir::{include_dir, Dir, File};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
 use std::{cell::RefCell, rc::Rc};
 
-use crate::{serve_canister_info, ENABLE_TEMPLATING};
+use crate::auth;
+use crate::config;
+use crate::metrics::render_metrics;
+use crate::upload;
+use crate::{
+    build_info_with, fetch_canister_status, record_phase_costs, serve_canister_info,
+    set_certified_data, DefiniteCanisterStatus, ENABLE_TEMPLATING,
+};
 
 thread_local! {
     static HTTP_TREE: Rc<RefCell<HttpCertificationTree>> = Default::default();
     static ASSET_ROUTER: RefCell<AssetRouter<'static>> = RefCell::new(AssetRouter::with_tree(HTTP_TREE.with(|tree| tree.clone())));
+    static DYNAMIC_ASSETS: RefCell<CertifiedAssets> = RefCell::default();
+    // Paths of the bundled assets and redirects, without the leading slash
+    static STATIC_PATHS: RefCell<BTreeSet<String>> = RefCell::default();
+    // Bundled assets and their encoded variants with their body length and hash
+    static STATIC_INVENTORY: RefCell<Vec<(String, u64)>> = RefCell::default();
+    static DEPLOYED_AT: RefCell<u64> = const { RefCell::new(0) };
+    // (revision, hash of the status and config) bumped whenever they change, restarts at 0
+    // after an upgrade
+    static STATUS_REVISION: RefCell<(u64, [u8; 32])> = const { RefCell::new((0, [0; 32])) };
+    // By (request path, content-encoding), see certify_encoding_overrides
+    static ENCODING_OVERRIDES: RefCell<BTreeMap<(String, String), CertifiedResponse>> = RefCell::default();
+    // See certify_custom_responses
+    static CUSTOM_RESPONSES: RefCell<BTreeMap<ResponseKey, CertifiedResponse>> = RefCell::default();
+}
+
+// Responses the router has no asset config for
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum ResponseKind {
+    InternalError,
+    NotFound,
+    Unauthorized,
+    Redirect,
+    // By the validator, as "{request header}={value}"
+    NotModified,
+    // By the head_variant of the GET response
+    Head,
+    // By language
+    LanguageRedirect,
+}
+
+// (kind, certified path, whether the path is a wildcard, variant of the kind)
+type ResponseKey = (ResponseKind, String, bool, String);
+
+// A response certified next to the router's, with the tree entry it's served with
+struct CertifiedResponse {
+    response: HttpResponse<'static>,
+    entry: HttpCertificationTreeEntry<'static>,
+}
+
+// Lets dashboards poll for changes, the status fields are inlined next to the revision
+#[derive(Serialize)]
+struct StatusStream<'a> {
+    revision: u64,
+    next_poll_after_secs: u64,
+    #[serde(flatten)]
+    status: &'a DefiniteCanisterStatus,
+}
+
+#[derive(Default)]
+struct CertifiedAssets {
+    assets: Vec<(String, Cow<'static, [u8]>)>,
+    // Pre-compressed variants of the assets, named by the encoding's file extension
+    encoded: Vec<(String, Cow<'static, [u8]>)>,
+    configs: Vec<AssetConfig>,
+    // Nanoseconds since the epoch, served as Last-Modified
+    modified_at: BTreeMap<String, u64>,
+    // The base path the assets were certified under
+    base_path: String,
+    // Set when these are a previous render, kept because the status couldn't be fetched
+    stale: bool,
 }
 
 static ASSETS_DIR: Dir<'_> = include_dir!("src/assets");
-const IMMUTABLE_ASSET_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+// Shorter max-ages are for rapid iteration, so they aren't marked immutable
+const IMMUTABLE_MIN_MAX_AGE_SECS: u64 = 86_400;
+const NO_CACHE_CONTROL: &str = "public, no-cache, no-store";
+// 1x1 transparent 32-bit ICO, served when no favicon.ico is bundled
+const DEFAULT_FAVICON: &[u8] = &[
+    // ICONDIR
+    0x00, 0x00, 0x01, 0x00, 0x01, 0x00,
+    // ICONDIRENTRY, 48 bytes of image data at offset 22
+    0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x20, 0x00, 0x30, 0x00, 0x00, 0x00, 0x16, 0x00, 0x00, 0x00,
+    // BITMAPINFOHEADER, the height covers both the XOR and AND masks
+    0x28, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x00, 0x20, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // Transparent BGRA pixel followed by the padded AND mask row
+    0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00,
+];
+// Served when no robots.txt is bundled, allows crawling everything
+const DEFAULT_ROBOTS_TXT: &[u8] = b"User-agent: *\nAllow: /\n";
+// The asset router certifies responses with response verification v2
+const CERTIFICATION_VERSION: &str = "2";
+const SECURITY_TXT_PATH: &str = ".well-known/security.txt";
+// security.txt must expire within a year, every full certification run renews it
+const SECURITY_TXT_LIFETIME_SECS: u64 = 31_536_000;
+// The language of index.hbs, requests preferring it are never redirected
+const DEFAULT_LANGUAGE: &str = "en";
+// (request header, the response header it sends back), If-None-Match taking precedence
+const CONDITIONAL_HEADERS: [(&str, &str); 2] = [
+    ("if-none-match", "etag"),
+    ("if-modified-since", "last-modified"),
+];
+// Describe the body a 304 doesn't have
+const BODY_HEADERS: [&str; 5] = [
+    "content-length",
+    "content-type",
+    "content-encoding",
+    "content-range",
+    "content-disposition",
+];
+// Each encoding the router can have a response for, identity first
+const ENCODINGS: [Option<AssetEncoding>; 5] = [
+    None,
+    Some(AssetEncoding::Brotli),
+    Some(AssetEncoding::Zstd),
+    Some(AssetEncoding::Gzip),
+    Some(AssetEncoding::Deflate),
+];
+const PLACEHOLDER_INDEX_HTML: &[u8] = b"<!DOCTYPE html><html lang=\"en\"><head><meta charset=\"UTF-8\"><title>Canister Status</title></head><body><h1>Canister status is not available yet</h1><p>Please check back in a few minutes.</p></body></html>";
+const MISSING_ASSETS_HTML: &[u8] = b"<!DOCTYPE html><html lang=\"en\"><head><meta charset=\"UTF-8\"><title>No assets</title></head><body><h1>No assets were bundled</h1><p>The assets directory was empty when this canister was built. Check the path passed to include_dir! and rebuild.</p></body></html>";
 
-pub(crate) fn serve_asset(req: &HttpRequest) -> HttpResponse<'static> {
-    ASSET_ROUTER.with_borrow(|asset_router| {
-        if let Ok(response) = asset_router.serve_asset(
-            &data_certificate().expect("No data certificate available"),
-            req,
-        ) {
+// The data certificate is only available in queries, replicated calls pass an empty one
+// and anything else gets a 500 rather than a trap
+pub(crate) fn serve_asset(
+    req: &HttpRequest,
+    data_certificate: Option<Vec<u8>>,
+) -> HttpResponse<'static> {
+    if !req.method().as_str().eq_ignore_ascii_case("HEAD") {
+        return serve_get(req, data_certificate);
+    }
+    let response = serve_get(req, data_certificate.clone());
+    match data_certificate {
+        Some(data_certificate) if response.upgrade() != Some(true) => {
+            head_response(req, response, &data_certificate)
+        }
+        _ => response,
+    }
+}
+
+// Anything but HEAD is answered like GET
+fn serve_get(req: &HttpRequest, data_certificate: Option<Vec<u8>>) -> HttpResponse<'static> {
+    let Some(data_certificate) = data_certificate else {
+        return internal_error_page("Certificate unavailable in this context");
+    };
+    // Any URL may need one, so it can't be certified ahead of the request
+    if let Some(location) = normalized_url(req.url()) {
+        let redirect = redirect(StatusCode::PERMANENT_REDIRECT, location, vec![]);
+        return uncertified_or_upgrade(&data_certificate, redirect);
+    }
+    // No asset is certified outside of the base path
+    let Some(path) = local_path(req) else {
+        return not_found_response(req, &data_certificate);
+    };
+    // The router serves those with a 301 or 307 itself
+    if let Some((target, status)) = config::with_config(|config| {
+        config
+            .alias_redirects
+            .get(&path)
+            .filter(|(_, status)| ![301, 307].contains(status))
+            .filter(|_| config.maintenance_retry_after_secs.is_none())
+            .cloned()
+    }) {
+        if let Some(response) = serve_custom(req, ResponseKind::Redirect, "", &data_certificate) {
+            return response;
+        }
+        let status = StatusCode::from_u16(status).unwrap_or(StatusCode::PERMANENT_REDIRECT);
+        let redirect = redirect(status, under_base_path(&base_path(), &target), vec![]);
+        return uncertified_or_upgrade(&data_certificate, redirect);
+    }
+    if serves_status(req) && !auth::is_authorized(req) {
+        return unauthorized_response(req, &data_certificate);
+    }
+    if let Some(language) = preferred_language(req) {
+        return language_redirect(req, &language, &data_certificate);
+    }
+    // In SPA mode index.html is certified as the fallback, so a missing file gets the
+    // 404 certified next to it
+    if spa_fallback() && looks_like_file(&path) && !is_certified(&path) {
+        return not_found_response(req, &data_certificate);
+    }
+
+    if let Some(response) = not_modified_response(req, &data_certificate) {
+        return response;
+    }
+
+    let gzip_only = gzip_preferred(req);
+    let req = gzip_only.as_ref().unwrap_or(req);
+    let served =
+        ASSET_ROUTER.with_borrow(|asset_router| asset_router.serve_asset(&data_certificate, req));
+    match (served, get_header(req.headers(), "range")) {
+        (Ok(response), None) => with_encoding_override(req, response, &data_certificate),
+        // The router certifies the chunks of assets larger than ASSET_CHUNK_SIZE along
+        // with the range they answer
+        (Ok(response), Some(_)) if response.status_code() == StatusCode::PARTIAL_CONTENT => {
             response
-        } else {
-            ic_cdk::trap("Failed to serve asset");
         }
-    })
+        // Any other range has no certified response, so it's cut from the full body in
+        // an update call, which is trusted through consensus instead
+        (served, Some(range)) if data_certificate.is_empty() => serve_range(req, served, range),
+        (_, Some(_)) => HttpResponse::builder().with_upgrade(true).build(),
+        // Every path is covered by a fallback, so this is never a missing asset
+        (Err(err), None) => internal_error_response(
+            req,
+            &data_certificate,
+            &format!("Failed to serve asset: {}", err),
+        ),
+    }
 }
 
-pub(crate) async fn certify_all_assets() {
-    let encodings = vec![
-        AssetEncoding::Brotli.default_config(),
-        AssetEncoding::Gzip.default_config(),
-    ];
+// The router ranks brotli above gzip in any Accept-Encoding, so with prefer_gzip it's
+// only shown gzip when the client accepts it. The encoding isn't part of the certified
+// request.
+fn gzip_preferred(req: &HttpRequest) -> Option<HttpRequest<'static>> {
+    if !config::with_config(|config| config.prefer_gzip) {
+        return None;
+    }
+    let accepts_gzip = get_header(req.headers(), "accept-encoding")?
+        .split(',')
+        .filter_map(|encoding| encoding.split(';').next())
+        .any(|encoding| encoding.trim().eq_ignore_ascii_case("gzip"));
+    if !accepts_gzip {
+        return None;
+    }
 
-    let asset_configs = vec![
-        AssetConfig::File {
-            path: "index.html".to_string(),
-            content_type: Some("text/html".to_string()),
-            headers: get_asset_headers(vec![(
-                "cache-control".to_string(),
-                "public, no-cache, no-store".to_string(),
-            )]),
-            fallback_for: vec![],
-            aliased_by: vec!["/".to_string()],
-            encodings: encodings.clone(),
-        },
-        AssetConfig::File {
-            path: "404.html".to_string(),
-            content_type: Some("text/html".to_string()),
-            headers: get_asset_headers(vec![(
-                "cache-control".to_string(),
-                "public, no-cache, no-store".to_string(),
-            )]),
-            fallback_for: vec![AssetFallbackConfig {
-                scope: "/".to_string(),
-                status_code: Some(StatusCode::NOT_FOUND),
-            }],
-            aliased_by: vec![],
-            encodings: encodings.clone(),
-        },
-        AssetConfig::Pattern {
-            pattern: "**/*.js".to_string(),
-            content_type: Some("text/javascript".to_string()),
-            headers: get_asset_headers(vec![(
-                "cache-control".to_string(),
-                IMMUTABLE_ASSET_CACHE_CONTROL.to_string(),
-            )]),
-            encodings: encodings.clone(),
-        },
-        AssetConfig::Pattern {
-            pattern: "**/*.css".to_string(),
-            content_type: Some("text/css".to_string()),
-            headers: get_asset_headers(vec![(
-                "cache-control".to_string(),
-                IMMUTABLE_ASSET_CACHE_CONTROL.to_string(),
-            )]),
-            encodings,
+    let headers = req
+        .headers()
+        .iter()
+        .filter(|(name, _)| !name.eq_ignore_ascii_case("accept-encoding"))
+        .cloned()
+        .chain([("accept-encoding".to_string(), "gzip".to_string())])
+        .collect();
+    let mut builder = HttpRequest::builder()
+        .with_method(req.method().clone())
+        .with_url(req.url())
+        .with_headers(headers)
+        .with_body(req.body().to_vec());
+    if let Some(version) = req.certificate_version() {
+        builder = builder.with_certificate_version(version);
+    }
+    Some(builder.build())
+}
+
+// The certificate of a response only covers the path it was certified at, so
+// //index.html or /index.html/ can't be served the /index.html response and are
+// redirected there instead. Collapses repeated slashes and drops a trailing one.
+fn normalized_url(url: &str) -> Option<String> {
+    let (path, query) = match url.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (url, None),
+    };
+    if !path.starts_with('/') {
+        return None;
+    }
+    let mut normalized = String::with_capacity(path.len());
+    for c in path.chars() {
+        if !(c == '/' && normalized.ends_with('/')) {
+            normalized.push(c);
+        }
+    }
+    if normalized.len() > 1 && normalized.ends_with('/') {
+        normalized.pop();
+    }
+    if normalized == path {
+        return None;
+    }
+    if let Some(query) = query {
+        normalized.push('?');
+        normalized.push_str(query);
+    }
+    Some(normalized)
+}
+
+// Whether the request gets the status page, in any of its formats. In SPA mode that
+// includes every client-side route, as they get index.html. Never during maintenance.
+fn serves_status(req: &HttpRequest) -> bool {
+    let Some(path) = local_path(req) else {
+        return false;
+    };
+    if config::with_config(|config| config.maintenance_retry_after_secs.is_some()) {
+        return false;
+    }
+    // Aliases are gated like the asset they serve
+    let target = config::with_config(|config| config.aliases.get(&path).cloned());
+    let path = target
+        .as_deref()
+        .unwrap_or(&path)
+        .trim_start_matches('/')
+        .trim_end_matches('/');
+    let is_page = |path: &str| path.starts_with("index") && path.ends_with(".html");
+    match path {
+        "" | "metrics" | "version.json" | "api/status.json" | "api/status-stream.json" => true,
+        path if is_page(path) || is_certified(&format!("index.{}.html", path)) => true,
+        path => {
+            config::with_config(|config| config.spa_mode)
+                && !looks_like_file(path)
+                && !is_certified(path)
+        }
+    }
+}
+
+// The first language of the Accept-Language header, by quality, that / has a
+// certified index.{lang}.html variant for
+fn preferred_language(req: &HttpRequest) -> Option<String> {
+    if local_path(req)? != "/" {
+        return None;
+    }
+    let mut languages: Vec<(f32, String)> = get_header(req.headers(), "accept-language")?
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            // Only the primary subtag is matched, fr-CA gets the fr variant
+            let language = tag.split('-').next()?.to_ascii_lowercase();
+            (quality > 0.0 && !language.is_empty()).then_some((quality, language))
+        })
+        .collect();
+    // Stable, so languages of equal quality keep their order
+    languages.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    languages
+        .into_iter()
+        .map(|(_, language)| language)
+        .take_while(|language| language != DEFAULT_LANGUAGE && language != "*")
+        .find(|language| is_certified(&format!("index.{}.html", language)))
+}
+
+fn language_redirect(
+    req: &HttpRequest,
+    language: &str,
+    data_certificate: &[u8],
+) -> HttpResponse<'static> {
+    serve_custom(
+        req,
+        ResponseKind::LanguageRedirect,
+        language,
+        data_certificate,
+    )
+    .unwrap_or_else(|| uncertified_or_upgrade(data_certificate, language_redirect_page(language)))
+}
+
+// Certified for / as is, the Accept-Language it depends on can't be
+fn language_redirect_page(language: &str) -> HttpResponse<'static> {
+    redirect(
+        StatusCode::TEMPORARY_REDIRECT,
+        under_base_path(&base_path(), &format!("/{}", language)),
+        vec![("vary".to_string(), "Accept-Language".to_string())],
+    )
+}
+
+// Alias and language redirects are certified next to the router's responses, the ones to
+// a normalized URL are answered by update calls
+fn redirect(
+    status_code: StatusCode,
+    location: String,
+    mut headers: Vec<HeaderField>,
+) -> HttpResponse<'static> {
+    headers.push(("location".to_string(), location));
+    headers.push(("cache-control".to_string(), "no-store".to_string()));
+    HttpResponse::builder()
+        .with_status_code(status_code)
+        .with_headers(headers)
+        .build()
+}
+
+// Only called in update calls, see serve_asset. Paths that aren't an asset, like client-side
+// routes, ignore the range and get the response the router served.
+fn serve_range(
+    req: &HttpRequest,
+    served: Result<HttpResponse<'static>, AssetCertificationError>,
+    range: &str,
+) -> HttpResponse<'static> {
+    let full = req.get_path().ok().and_then(|path| {
+        ASSET_ROUTER
+            .with_borrow(|asset_router| asset_router.get_assets().get(path, None, None).cloned())
+    });
+    let Some(response) = full else {
+        return served.unwrap_or_else(|err| {
+            internal_error_response(req, &[], &format!("Failed to serve asset: {}", err))
+        });
+    };
+    let body = response.body();
+    // The router's length describes the full body
+    let mut headers: Vec<HeaderField> = response
+        .headers()
+        .iter()
+        .filter(|(name, _)| !name.eq_ignore_ascii_case("content-length"))
+        .cloned()
+        .collect();
+    let Some((start, end)) = parse_range(range, body.len()) else {
+        headers.push((
+            "content-range".to_string(),
+            format!("bytes */{}", body.len()),
+        ));
+        return HttpResponse::builder()
+            .with_status_code(StatusCode::RANGE_NOT_SATISFIABLE)
+            .with_headers(headers)
+            .build();
+    };
+
+    // Partial responses may be shorter than requested, this keeps them within a reply
+    let end = end.min(start + ASSET_CHUNK_SIZE - 1);
+    headers.push((
+        "content-range".to_string(),
+        format!("bytes {}-{}/{}", start, end, body.len()),
+    ));
+    HttpResponse::builder()
+        .with_status_code(StatusCode::PARTIAL_CONTENT)
+        .with_headers(headers)
+        .with_body(body[start..=end].to_vec())
+        .build()
+}
+
+// Parses a `bytes=` range into inclusive offsets. Only the first range of a
+// multi-range request is served.
+fn parse_range(range: &str, len: usize) -> Option<(usize, usize)> {
+    let spec = range.trim().strip_prefix("bytes=")?.split(',').next()?;
+    let (start, end) = spec.trim().split_once('-')?;
+    let last = len.checked_sub(1)?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<usize>().ok()? {
+            0 => return None,
+            suffix => (len.saturating_sub(suffix), last),
         },
-    ];
+        (start, "") => (start.parse().ok()?, last),
+        (start, end) => (start.parse().ok()?, end.parse::<usize>().ok()?.min(last)),
+    };
+    (start <= end).then_some((start, end))
+}
 
-    let mut assets = Vec::new();
-    for file in ASSETS_DIR.files() {
-        let path = file.path().to_string_lossy();
-        // Special case for templating
-        if path.ends_with("index.hbs") {
-            if ENABLE_TEMPLATING {
-                let asset = Cow::Owned(serve_canister_info(file).await.as_bytes().to_vec());
-                assets.push(Asset::new("index.html", asset));
-            } else {
-                assets.push(Asset::new("index.html", file.contents()));
+// The HEAD response certified with the status and headers of the GET one, see
+// certify_head_responses
+fn head_response(
+    req: &HttpRequest,
+    response: HttpResponse<'static>,
+    data_certificate: &[u8],
+) -> HttpResponse<'static> {
+    let variant = head_variant(response.status_code(), response.headers());
+    serve_custom(req, ResponseKind::Head, &variant, data_certificate)
+        .unwrap_or_else(|| uncertified_or_upgrade(data_certificate, without_body(response)))
+}
+
+// Identifies a GET response by what a HEAD request gets of it
+fn head_variant(status_code: StatusCode, headers: &[HeaderField]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(status_code.as_u16().to_be_bytes());
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case("ic-certificate")
+            || name.eq_ignore_ascii_case(CERTIFICATE_EXPRESSION_HEADER_NAME)
+        {
+            continue;
+        }
+        hasher.update(name.to_ascii_lowercase());
+        hasher.update(b": ");
+        hasher.update(value);
+        hasher.update(b"\n");
+    }
+    hex::encode(hasher.finalize())
+}
+
+fn without_body(response: HttpResponse<'static>) -> HttpResponse<'static> {
+    let mut headers = response.headers().to_vec();
+    if get_header(&headers, "content-length").is_none() {
+        headers.push((
+            "content-length".to_string(),
+            response.body().len().to_string(),
+        ));
+    }
+    HttpResponse::builder()
+        .with_status_code(response.status_code())
+        .with_headers(headers)
+        .build()
+}
+
+pub(crate) fn root_hash() -> Vec<u8> {
+    ASSET_ROUTER.with_borrow(|asset_router| asset_router.root_hash().to_vec())
+}
+
+// Redirects count as certified entries, encoded variants and aliases don't
+pub(crate) fn certified_asset_count() -> usize {
+    STATIC_PATHS.with_borrow(|paths| paths.len())
+        + DYNAMIC_ASSETS.with_borrow(|certified| certified.assets.len())
+}
+
+// Encoded variants are listed under their own path, e.g. index.js.br, with the
+// compressed length. The identity entry reports the uncompressed length.
+pub(crate) fn list_assets() -> Vec<(String, u64)> {
+    full_inventory()
+}
+
+// (expression path, hex SHA-256 of the expression, hex response hash) of every response in
+// HTTP_TREE, the leaves short of their request hashes. The path's segments are joined with
+// `/`, e.g. `http_expr/index.html/<$>`, and every chunk of a large asset has an entry.
+pub(crate) fn dump_certification_tree() -> Vec<(String, String, String)> {
+    let mut certified: Vec<(HttpCertificationPath<'static>, HttpResponse<'static>)> = vec![];
+    ASSET_ROUTER.with_borrow(|asset_router| {
+        let urls = certified_urls().into_iter().map(|url| (url, false));
+        let scopes = custom_scopes(asset_router)
+            .into_iter()
+            .map(|scope| (scope, true));
+        for (url, wildcard) in urls.chain(scopes) {
+            for encoding in ENCODINGS {
+                let ranges = std::iter::once(None)
+                    .chain((0..).map(|chunk| Some(chunk * ASSET_CHUNK_SIZE)))
+                    .map_while(|range| {
+                        if wildcard {
+                            asset_router
+                                .get_fallback_assets()
+                                .get(url.as_str(), encoding, range)
+                        } else {
+                            asset_router.get_assets().get(url.as_str(), encoding, range)
+                        }
+                    });
+                for response in ranges {
+                    let path = if wildcard {
+                        HttpCertificationPath::wildcard(url.clone())
+                    } else {
+                        HttpCertificationPath::exact(url.clone())
+                    };
+                    certified.push((path, response.clone()));
+                }
             }
-        } else {
-            assets.push(Asset::new(path, file.contents()));
         }
+    });
+    let mut add = |responses: &mut dyn Iterator<Item = &CertifiedResponse>| {
+        certified.extend(responses.map(|response| {
+            (
+                response.entry.path.clone().into_owned(),
+                response.response.clone(),
+            )
+        }));
+    };
+    ENCODING_OVERRIDES.with_borrow(|overrides| add(&mut overrides.values()));
+    CUSTOM_RESPONSES.with_borrow(|responses| add(&mut responses.values()));
+
+    let cel_expr = full_cel_expr(&[]);
+    certified
+        .into_iter()
+        .map(|(path, response)| {
+            let cel = get_header(response.headers(), CERTIFICATE_EXPRESSION_HEADER_NAME)
+                .unwrap_or_default();
+            (
+                path.to_expr_path().join("/"),
+                hex::encode(Sha256::digest(cel.as_bytes())),
+                hex::encode(response_hash(&response, &cel_expr.response, None)),
+            )
+        })
+        .collect()
+}
+
+fn full_inventory() -> Vec<(String, u64)> {
+    let mut assets = STATIC_INVENTORY.with_borrow(|inventory| inventory.clone());
+    assets.extend(
+        DYNAMIC_ASSETS.with_borrow(|certified| inventory(&certified.assets, &certified.encoded)),
+    );
+    assets.sort();
+    assets
+}
+
+// The identity body the router certified for the path, redirects have none
+pub(crate) fn asset_bytes(path: &str) -> Option<Vec<u8>> {
+    let path = under_base_path(&base_path(), &format!("/{}", path.trim_start_matches('/')));
+    ASSET_ROUTER.with_borrow(|asset_router| {
+        asset_router
+            .get_assets()
+            .get(path, None, None)
+            .filter(|response| response.status_code() == StatusCode::OK)
+            .map(|response| response.body().to_vec())
+    })
+}
+
+fn inventory(
+    assets: &[(String, Cow<'static, [u8]>)],
+    encoded: &[(String, Cow<'static, [u8]>)],
+) -> Vec<(String, u64)> {
+    assets
+        .iter()
+        .chain(encoded)
+        .map(|(path, content)| (path.clone(), content.len() as u64))
+        .collect()
+}
+
+// Client-side routes don't have an extension in their last segment
+fn looks_like_file(path: &str) -> bool {
+    path.rsplit('/')
+        .next()
+        .is_some_and(|segment| segment.contains('.'))
+}
+
+pub(crate) fn is_certified(path: &str) -> bool {
+    let path = path.trim_start_matches('/');
+    STATIC_PATHS.with_borrow(|paths| paths.contains(path))
+        || DYNAMIC_ASSETS
+            .with_borrow(|certified| certified.assets.iter().any(|(asset, _)| asset == path))
+}
+
+fn spa_fallback() -> bool {
+    config::with_config(|config| config.spa_mode && config.maintenance_retry_after_secs.is_none())
+}
+
+fn not_found_response(req: &HttpRequest, data_certificate: &[u8]) -> HttpResponse<'static> {
+    serve_custom(req, ResponseKind::NotFound, "", data_certificate)
+        .unwrap_or_else(|| uncertified_or_upgrade(data_certificate, not_found_page()))
+}
+
+// The certified 304 when the request sends a validator of the response back as is, only
+// the first conditional header it has counts. Any other value gets the full response, also
+// a later If-Modified-Since date, as only the exact one can be certified.
+fn not_modified_response(
+    req: &HttpRequest,
+    data_certificate: &[u8],
+) -> Option<HttpResponse<'static>> {
+    if !req.method().as_str().eq_ignore_ascii_case("GET")
+        || get_header(req.headers(), "range").is_some()
+    {
+        return None;
+    }
+    let (name, value) = CONDITIONAL_HEADERS
+        .iter()
+        .find_map(|(name, _)| Some((name, get_header(req.headers(), name)?)))?;
+    let variant = format!("{}={}", name, value);
+    serve_custom(req, ResponseKind::NotModified, &variant, data_certificate)
+}
+
+fn unauthorized_response(req: &HttpRequest, data_certificate: &[u8]) -> HttpResponse<'static> {
+    serve_custom(req, ResponseKind::Unauthorized, "", data_certificate)
+        .unwrap_or_else(|| uncertified_or_upgrade(data_certificate, auth::unauthorized()))
+}
+
+fn not_found_page() -> HttpResponse<'static> {
+    page_response(StatusCode::NOT_FOUND, "404.html", "Not found")
+}
+
+fn internal_error_page(message: &str) -> HttpResponse<'static> {
+    page_response(StatusCode::INTERNAL_SERVER_ERROR, "500.html", message)
+}
+
+// Queries without a certified response are upgraded, so the update call answers and is
+// trusted through consensus instead
+fn uncertified_or_upgrade(
+    data_certificate: &[u8],
+    response: HttpResponse<'static>,
+) -> HttpResponse<'static> {
+    if data_certificate.is_empty() {
+        response
+    } else {
+        HttpResponse::builder().with_upgrade(true).build()
+    }
+}
+
+// The 500 certified for every scope, which can't carry the message. It's only in the
+// response when there's none.
+fn internal_error_response(
+    req: &HttpRequest,
+    data_certificate: &[u8],
+    message: &str,
+) -> HttpResponse<'static> {
+    serve_custom(req, ResponseKind::InternalError, "", data_certificate)
+        .unwrap_or_else(|| uncertified_or_upgrade(data_certificate, internal_error_page(message)))
+}
+
+// The bundled page for the status, or the plain message when there's none
+fn page_response(status_code: StatusCode, page: &str, message: &str) -> HttpResponse<'static> {
+    let Some(file) = ASSETS_DIR.get_file(page) else {
+        return error_response(status_code, message);
+    };
+    HttpResponse::builder()
+        .with_status_code(status_code)
+        .with_headers(get_asset_headers(vec![
+            ("content-type".to_string(), "text/html".to_string()),
+            ("cache-control".to_string(), NO_CACHE_CONTROL.to_string()),
+        ]))
+        .with_body(file.contents().to_vec())
+        .build()
+}
+
+// Uncertified, only used when no certified response can be produced
+pub(crate) fn error_response(status_code: StatusCode, message: &str) -> HttpResponse<'static> {
+    HttpResponse::builder()
+        .with_status_code(status_code)
+        .with_headers(vec![
+            ("content-type".to_string(), "text/plain".to_string()),
+            ("cache-control".to_string(), "no-store".to_string()),
+        ])
+        .with_body(message.as_bytes().to_vec())
+        .build()
+}
+
+pub(crate) fn get_header<'a>(headers: &'a [HeaderField], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+// Only synthesized once a contact is configured, the field is required
+fn security_txt() -> Option<String> {
+    let contact = config::with_config(|config| config.security_contact.clone())?;
+    let expires = crate::now() + SECURITY_TXT_LIFETIME_SECS * 1_000_000_000;
+    let expires = DateTime::from_timestamp_nanos(expires as i64).format("%Y-%m-%dT%H:%M:%SZ");
+    Some(format!("Contact: {}\nExpires: {}\n", contact, expires))
+}
+
+fn http_date(nanos: u64) -> String {
+    DateTime::from_timestamp_nanos(nanos as i64)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+// An error means the status couldn't be rendered and the last render was certified instead
+pub(crate) async fn certify_all_assets() -> Result<(), String> {
+    if let Some(retry_after_secs) =
+        config::with_config(|config| config.maintenance_retry_after_secs)
+    {
+        certify_maintenance(retry_after_secs);
+        return Ok(());
+    }
+
+    let rendered = dynamic_assets().await;
+    certify_all_with(rendered)
+}
+
+// The assets are certified next to the render of the canister status, or what's left
+// of it when rendering failed
+fn certify_all_with(rendered: Result<AssetContents, String>) -> Result<(), String> {
+    // Keep the last good render when the canister status is unavailable, or a
+    // placeholder if there never was one, so the site is always served
+    let (mut dynamic_assets, outcome) = match rendered {
+        Ok(assets) => (assets, Ok(())),
+        Err(err) => (
+            DYNAMIC_ASSETS.with_borrow(|certified| certified.assets.clone()),
+            Err(err),
+        ),
+    };
+    let stale = outcome.is_err() && !dynamic_assets.is_empty();
+    if ENABLE_TEMPLATING && dynamic_assets.is_empty() {
+        dynamic_assets.push((
+            "index.html".to_string(),
+            Cow::Borrowed(PLACEHOLDER_INDEX_HTML),
+        ));
+    }
+    let (mut static_assets, precompressed) = split_precompressed(static_assets());
+    add_missing_assets_placeholder(&ASSETS_DIR, &mut static_assets, &dynamic_assets);
+    let asset_configs = asset_configs();
+    let mut uploaded_configs = uploaded_asset_configs();
+    uploaded_configs.extend(asset_configs.iter().cloned());
+    let static_encoded = encode_assets(&uploaded_configs, &static_assets, &precompressed);
+    let dynamic_encoded = encode_assets(&asset_configs, &dynamic_assets, &[]);
+    let dynamic_modified_at =
+        DYNAMIC_ASSETS.with_borrow(|previous| dynamic_modified_at(&dynamic_assets, previous));
+    let mut dynamic_configs = specialize_asset_configs(
+        &asset_configs,
+        &dynamic_assets,
+        &dynamic_encoded,
+        &dynamic_modified_at,
+    );
+    if stale {
+        dynamic_configs = with_stale_header(dynamic_configs);
+    }
+    let mut static_configs = specialize_asset_configs(
+        &uploaded_configs,
+        &static_assets,
+        &static_encoded,
+        &static_modified_at(&static_assets),
+    );
+    static_configs.extend(redirect_configs());
+    let static_paths = static_assets
+        .iter()
+        .map(|(path, _)| path.clone())
+        .chain(config::with_config(|config| {
+            config
+                .redirects
+                .keys()
+                .chain(config.alias_redirects.keys())
+                .map(|from| from.trim_start_matches('/').to_string())
+                .collect::<Vec<_>>()
+        }))
+        .collect();
+    let static_inventory = inventory(&static_assets, &static_encoded);
+
+    // Start from an empty tree so no stale responses stay certified
+    HTTP_TREE.with(|tree| *tree.borrow_mut() = HttpCertificationTree::default());
+    ENCODING_OVERRIDES.take();
+    CUSTOM_RESPONSES.take();
+    ASSET_ROUTER.with_borrow_mut(|asset_router| {
+        *asset_router = AssetRouter::with_tree(HTTP_TREE.with(|tree| tree.clone()));
+        certify(
+            asset_router,
+            [static_assets, static_encoded].concat(),
+            static_configs,
+        );
+        certify(
+            asset_router,
+            [dynamic_assets.clone(), dynamic_encoded.clone()].concat(),
+            dynamic_configs.clone(),
+        );
+    });
+    DYNAMIC_ASSETS.set(CertifiedAssets {
+        assets: dynamic_assets,
+        encoded: dynamic_encoded,
+        configs: dynamic_configs,
+        modified_at: dynamic_modified_at,
+        base_path: base_path(),
+        stale,
+    });
+    STATIC_PATHS.set(static_paths);
+    STATIC_INVENTORY.set(static_inventory);
+    publish_certification();
+    outcome
+}
+
+// Only replaces the assets generated from the canister status, the bundled
+// assets stay certified from the last full run.
+pub(crate) async fn certify_dynamic_assets() -> Result<(), String> {
+    if config::with_config(|config| config.maintenance_retry_after_secs.is_some()) {
+        return Ok(());
+    }
+    let rendered = dynamic_assets().await;
+    certify_dynamic_with(rendered)
+}
+
+fn certify_dynamic_with(rendered: Result<AssetContents, String>) -> Result<(), String> {
+    // Keep serving the last certified render until the status can be fetched again
+    let assets = match rendered {
+        Ok(assets) => assets,
+        Err(err) => {
+            mark_dynamic_assets_stale();
+            return Err(err);
+        }
+    };
+    let asset_configs = asset_configs();
+    // Compressed from the rendered bytes, the bundled templates are never served
+    let encoded = encode_assets(&asset_configs, &assets, &[]);
+    let modified_at = DYNAMIC_ASSETS.with_borrow(|previous| dynamic_modified_at(&assets, previous));
+    let configs = specialize_asset_configs(&asset_configs, &assets, &encoded, &modified_at);
+    replace_dynamic_assets(CertifiedAssets {
+        assets,
+        encoded,
+        configs,
+        modified_at,
+        base_path: base_path(),
+        stale: false,
+    });
+    Ok(())
+}
+
+// Re-certifies the last render with an x-content-stale header, so clients can tell it
+// apart from a fresh one. The next successful render replaces it.
+fn mark_dynamic_assets_stale() {
+    let (assets, encoded, configs, modified_at) = DYNAMIC_ASSETS.with_borrow(|certified| {
+        (
+            certified.assets.clone(),
+            certified.encoded.clone(),
+            certified.configs.clone(),
+            certified.modified_at.clone(),
+        )
+    });
+    if assets.is_empty() || DYNAMIC_ASSETS.with_borrow(|certified| certified.stale) {
+        return;
     }
+    replace_dynamic_assets(CertifiedAssets {
+        assets,
+        encoded,
+        configs: with_stale_header(configs),
+        modified_at,
+        base_path: base_path(),
+        stale: true,
+    });
+}
+
+fn with_stale_header(configs: Vec<AssetConfig>) -> Vec<AssetConfig> {
+    configs
+        .into_iter()
+        .map(|config| match config {
+            AssetConfig::File {
+                path,
+                content_type,
+                mut headers,
+                fallback_for,
+                aliased_by,
+                encodings,
+            } => {
+                headers.push(("x-content-stale".to_string(), "true".to_string()));
+                AssetConfig::File {
+                    path,
+                    content_type,
+                    headers,
+                    fallback_for,
+                    aliased_by,
+                    encodings,
+                }
+            }
+            config => config,
+        })
+        .collect()
+}
+
+fn replace_dynamic_assets(certified: CertifiedAssets) {
+    let previous = DYNAMIC_ASSETS.take();
+    ASSET_ROUTER.with_borrow_mut(|asset_router| {
+        let previous_configs: Vec<AssetConfig> = previous
+            .configs
+            .into_iter()
+            .map(|config| config_under_base_path(&previous.base_path, config))
+            .collect();
+        for batch in tree_order_batches([previous.assets, previous.encoded].concat()) {
+            let batch = batch.into_iter().map(|(path, content)| {
+                Asset::new(under_base_path(&previous.base_path, &path), content)
+            });
+            if let Err(err) = asset_router.delete_assets(batch, previous_configs.clone()) {
+                ic_cdk::trap(&format!("Failed to delete assets: {}", err));
+            }
+        }
+        certify(
+            asset_router,
+            [certified.assets.clone(), certified.encoded.clone()].concat(),
+            certified.configs.clone(),
+        );
+    });
+    DYNAMIC_ASSETS.set(certified);
+    publish_certification();
+}
 
+// Only the liveness probe and the maintenance page are certified, the page being the
+// fallback for every other path
+fn certify_maintenance(retry_after_secs: u32) {
+    let assets: Vec<(String, Cow<'static, [u8]>)> = static_assets()
+        .into_iter()
+        .filter(|(path, _)| path == "healthz" || path == "maintenance.html")
+        .collect();
+    let mut base_configs = vec![maintenance_config(retry_after_secs)];
+    base_configs.extend(asset_configs());
+    let encoded = encode_assets(&base_configs, &assets, &[]);
+    let configs = specialize_asset_configs(
+        &base_configs,
+        &assets,
+        &encoded,
+        &static_modified_at(&assets),
+    );
+    let paths = assets.iter().map(|(path, _)| path.clone()).collect();
+    let inventory = inventory(&assets, &encoded);
+
+    HTTP_TREE.with(|tree| *tree.borrow_mut() = HttpCertificationTree::default());
+    ENCODING_OVERRIDES.take();
+    CUSTOM_RESPONSES.take();
     ASSET_ROUTER.with_borrow_mut(|asset_router| {
-        if let Err(err) = asset_router.certify_assets(assets, asset_configs) {
+        *asset_router = AssetRouter::with_tree(HTTP_TREE.with(|tree| tree.clone()));
+        certify(asset_router, [assets, encoded].concat(), configs);
+    });
+    DYNAMIC_ASSETS.set(CertifiedAssets::default());
+    STATIC_PATHS.set(paths);
+    STATIC_INVENTORY.set(inventory);
+    publish_certification();
+}
+
+pub(crate) fn record_deploy_time() {
+    DEPLOYED_AT.set(crate::now());
+}
+
+// Bundled assets have no modification time of their own, so they date from the deploy
+fn static_modified_at(assets: &[(String, Cow<'static, [u8]>)]) -> BTreeMap<String, u64> {
+    let deployed_at = DEPLOYED_AT.with_borrow(|at| *at);
+    upload::with_uploaded_assets(|uploaded| {
+        assets
+            .iter()
+            .map(|(path, _)| {
+                let modified_at = uploaded
+                    .get(path)
+                    .map_or(deployed_at, |asset| asset.uploaded_at);
+                (path.clone(), modified_at)
+            })
+            .collect()
+    })
+}
+
+// A render keeps the time of the previous one while its content doesn't change
+fn dynamic_modified_at(
+    assets: &[(String, Cow<'static, [u8]>)],
+    previous: &CertifiedAssets,
+) -> BTreeMap<String, u64> {
+    let now = crate::now();
+    assets
+        .iter()
+        .map(|(path, content)| {
+            let unchanged = previous
+                .assets
+                .iter()
+                .any(|(previous_path, previous_content)| {
+                    previous_path == path && previous_content == content
+                });
+            let modified_at = previous
+                .modified_at
+                .get(path)
+                .filter(|_| unchanged)
+                .copied()
+                .unwrap_or(now);
+            (path.clone(), modified_at)
+        })
+        .collect()
+}
+
+fn certify(
+    asset_router: &mut AssetRouter<'static>,
+    assets: Vec<(String, Cow<'static, [u8]>)>,
+    asset_configs: Vec<AssetConfig>,
+) {
+    let base_path = base_path();
+    let asset_configs: Vec<AssetConfig> = asset_configs
+        .into_iter()
+        .map(|config| config_under_base_path(&base_path, config))
+        .collect();
+    let mut urls: Vec<String> = assets
+        .iter()
+        .map(|(path, _)| under_base_path(&base_path, path))
+        .collect();
+    for config in &asset_configs {
+        if let AssetConfig::File { aliased_by, .. } = config {
+            urls.extend(aliased_by.iter().cloned());
+        }
+    }
+    for batch in tree_order_batches(assets) {
+        let batch = batch
+            .into_iter()
+            .map(|(path, content)| Asset::new(under_base_path(&base_path, &path), content));
+        if let Err(err) = asset_router.certify_assets(batch, asset_configs.clone()) {
             ic_cdk::trap(&format!("Failed to certify assets: {}", err));
         }
+    }
+    certify_encoding_overrides(asset_router, &urls);
+}
+
+// The router adds the assets of a call to the tree in hash map order, and the shape of
+// the tree, so the root hash, depends on the order entries are added and removed in.
+// Encoded variants and assets without any come one per batch in path order, then every
+// other asset with its variants, which by then only adds or removes the responses of the
+// asset itself. The same assets then always give the same root hash.
+fn tree_order_batches(assets: AssetContents) -> Vec<AssetContents> {
+    let extensions = encoding_extensions();
+    let (mut assets, variants) = split_precompressed(assets);
+    assets.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut singles: Vec<AssetContents> = variants.iter().cloned().map(|v| vec![v]).collect();
+    let mut groups = Vec::new();
+    for asset in assets {
+        let mut group: AssetContents = variants
+            .iter()
+            .filter(|(path, _)| {
+                extensions
+                    .iter()
+                    .any(|extension| path.strip_suffix(extension.as_str()) == Some(&asset.0))
+            })
+            .cloned()
+            .collect();
+        if group.is_empty() {
+            singles.push(vec![asset]);
+        } else {
+            group.insert(0, asset);
+            groups.push(group);
+        }
+    }
+    singles.sort_by(|a, b| a[0].0.cmp(&b[0].0));
+    singles.extend(groups);
+    singles
+}
+
+// The router serves every encoding with the headers of its asset config, so the variants
+// of an encoding with a cache-control of its own are certified again next to the router's
+// responses, with only that header replaced. Chunked assets keep the one of their config.
+fn certify_encoding_overrides(asset_router: &AssetRouter<'static>, urls: &[String]) {
+    let overrides = config::with_config(|config| config.encoding_cache_control.clone());
+    let urls: Vec<String> = urls
+        .iter()
+        .map(|url| format!("/{}", url.trim_start_matches('/')))
+        .collect();
+    let previous: Vec<CertifiedResponse> = ENCODING_OVERRIDES.with_borrow_mut(|certified| {
+        let keys: Vec<(String, String)> = certified
+            .keys()
+            .filter(|(url, _)| urls.contains(url))
+            .cloned()
+            .collect();
+        keys.iter()
+            .filter_map(|key| certified.remove(key))
+            .collect()
+    });
+    HTTP_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        for certified in &previous {
+            tree.delete(&certified.entry);
+        }
+    });
+
+    for url in &urls {
+        for (name, cache_control) in &overrides {
+            let Some(encoding) = config::parse_encoding(name) else {
+                continue;
+            };
+            let Some(response) = asset_router.get_assets().get(url, Some(encoding), None) else {
+                continue;
+            };
+            if response.body().len() > ASSET_CHUNK_SIZE {
+                continue;
+            }
+            let mut headers: Vec<HeaderField> = response
+                .headers()
+                .iter()
+                .filter(|(name, _)| !name.eq_ignore_ascii_case("cache-control"))
+                .cloned()
+                .collect();
+            headers.push(("cache-control".to_string(), cache_control.clone()));
+            let certified = certified_response(
+                HttpCertificationPath::exact(url.clone()),
+                &HttpRequest::get(url.clone()).build(),
+                &[],
+                response.status_code(),
+                headers,
+                response.body().to_vec(),
+            );
+            HTTP_TREE.with(|tree| tree.borrow_mut().insert(&certified.entry));
+            ENCODING_OVERRIDES.with_borrow_mut(|overrides| {
+                overrides.insert((url.clone(), name.clone()), certified)
+            });
+        }
+    }
+}
+
+// Certifies the responses derived from the assets, once they're all certified and
+// recorded, then publishes the root hash covering both
+fn publish_certification() {
+    ASSET_ROUTER.with_borrow(|asset_router| {
+        certify_custom_responses(asset_router);
         set_certified_data(&asset_router.root_hash());
     });
 }
 
-fn get_asset_headers(additional_headers: Vec<HeaderField>) -> Vec<HeaderField> {
-    let mut headers = vec![
-        ("strict-transport-security".to_string(), "max-age=31536000; includeSubDomains".to_string()),
-        ("x-frame-options".to_string(), "DENY".to_string()),
-        ("x-content-type-options".to_string(), "nosniff".to_string()),
-        ("content-security-policy".to_string(), "default-src 'self'; img-src 'self' data:; form-action 'self'; object-src 'none'; frame-ancestors 'none'; upgrade-insecure-requests; block-all-mixed-content".to_string()),
-        ("referrer-policy".to_string(), "no-referrer".to_string()),
-        ("permissions-policy".to_string(), "accelerometer=(),ambient-light-sensor=(),autoplay=(),battery=(),camera=(),display-capture=(),document-domain=(),encrypted-media=(),fullscreen=(),gamepad=(),geolocation=(),gyroscope=(),layout-animations=(self),legacy-image-formats=(self),magnetometer=(),microphone=(),midi=(),oversized-images=(self),payment=(),picture-in-picture=(),publickey-credentials-get=(),speaker-selection=(),sync-xhr=(self),unoptimized-images=(self),unsized-media=(self),usb=(),screen-wake-lock=(),web-share=(),xr-spatial-tracking=()".to_string()),
-        ("cross-origin-embedder-policy".to_string(), "require-corp".to_string()),
-        ("cross-origin-opener-policy".to_string(), "same-origin".to_string()),
-    ];
-    headers.extend(additional_headers);
-    headers
+// Certifies the responses the router has no config for next to its own. They're derived
+// from what the router serves, so they're replaced after every change to it, in key order
+// so the same assets always give the same root hash. Wildcards are certified for the base
+// path and every scope the router has a fallback for, which keeps them the most specific
+// wildcard for the URLs they answer.
+fn certify_custom_responses(asset_router: &AssetRouter<'static>) {
+    let previous = CUSTOM_RESPONSES.take();
+    let base_path = base_path();
+    let scopes = custom_scopes(asset_router);
+    let mut responses = BTreeMap::new();
+    let spa_fallback = spa_fallback();
+    for scope in &scopes {
+        let error_page = internal_error_page("Internal server error");
+        certify_page(
+            &mut responses,
+            ResponseKind::InternalError,
+            "",
+            scope,
+            true,
+            error_page,
+        );
+        // Missing files, as index.html is the fallback of every other path
+        if spa_fallback {
+            certify_page(
+                &mut responses,
+                ResponseKind::NotFound,
+                "",
+                scope,
+                true,
+                not_found_page(),
+            );
+        }
+    }
+    // Paths outside of the base path
+    if !base_path.is_empty() {
+        certify_page(
+            &mut responses,
+            ResponseKind::NotFound,
+            "",
+            "/",
+            true,
+            not_found_page(),
+        );
+    }
+    // Alias redirects with a status the router can't certify, none during maintenance
+    let alias_redirects = config::with_config(|config| match config.maintenance_retry_after_secs {
+        Some(_) => BTreeMap::new(),
+        None => config.alias_redirects.clone(),
+    });
+    for (alias, (target, status)) in alias_redirects {
+        let Ok(status) = StatusCode::from_u16(status) else {
+            continue;
+        };
+        if status == StatusCode::MOVED_PERMANENTLY || status == StatusCode::TEMPORARY_REDIRECT {
+            continue;
+        }
+        let url = under_base_path(&base_path, &alias);
+        let redirect = redirect(status, under_base_path(&base_path, &target), vec![]);
+        certify_page(
+            &mut responses,
+            ResponseKind::Redirect,
+            "",
+            &url,
+            false,
+            redirect,
+        );
+    }
+    // One redirect per language / may get, for whichever Accept-Language prefers it
+    let root = under_base_path(&base_path, "/");
+    for language in certified_languages() {
+        let redirect = language_redirect_page(&language);
+        certify_page(
+            &mut responses,
+            ResponseKind::LanguageRedirect,
+            &language,
+            &root,
+            false,
+            redirect,
+        );
+    }
+    // A 304 for each validator of a 200, certified along with the request header sending
+    // it back as is
+    for url in certified_urls() {
+        let Some(response) = asset_router.get_assets().get(url.as_str(), None, None) else {
+            continue;
+        };
+        if response.status_code() != StatusCode::OK || response.body().len() > ASSET_CHUNK_SIZE {
+            continue;
+        }
+        let headers: Vec<HeaderField> = response
+            .headers()
+            .iter()
+            .filter(|(name, _)| {
+                !BODY_HEADERS
+                    .iter()
+                    .any(|body| name.eq_ignore_ascii_case(body))
+            })
+            .cloned()
+            .collect();
+        for (request_header, validator) in CONDITIONAL_HEADERS {
+            let Some(value) = get_header(response.headers(), validator) else {
+                continue;
+            };
+            let request = HttpRequest::get(url.clone())
+                .with_headers(vec![(request_header.to_string(), value.to_string())])
+                .build();
+            let certified = certified_response(
+                HttpCertificationPath::exact(url.clone()),
+                &request,
+                &[request_header],
+                StatusCode::NOT_MODIFIED,
+                headers.clone(),
+                vec![],
+            );
+            let variant = format!("{}={}", request_header, value);
+            responses.insert(
+                (ResponseKind::NotModified, url.clone(), false, variant),
+                certified,
+            );
+        }
+    }
+    // The status page in each format, client-side routes included as they get index.html
+    if config::with_config(|config| config.metrics_auth.is_some()) {
+        for path in status_paths() {
+            let url = under_base_path(&base_path, &path);
+            if asset_router
+                .get_assets()
+                .get(url.as_str(), None, None)
+                .is_some()
+            {
+                certify_page(
+                    &mut responses,
+                    ResponseKind::Unauthorized,
+                    "",
+                    &url,
+                    false,
+                    auth::unauthorized(),
+                );
+            }
+        }
+        if spa_fallback {
+            for scope in &scopes {
+                certify_page(
+                    &mut responses,
+                    ResponseKind::Unauthorized,
+                    "",
+                    scope,
+                    true,
+                    auth::unauthorized(),
+                );
+            }
+        }
+    }
+    certify_head_responses(asset_router, &scopes, &mut responses);
+
+    HTTP_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        for certified in previous.values() {
+            tree.delete(&certified.entry);
+        }
+        for certified in responses.values() {
+            tree.insert(&certified.entry);
+        }
+    });
+    CUSTOM_RESPONSES.set(responses);
+}
+
+// A HEAD response for each certified GET one but the 304s, with its status and headers,
+// content-length included, and no body. The GET responses of a path with equal headers
+// share one.
+fn certify_head_responses(
+    asset_router: &AssetRouter<'static>,
+    scopes: &BTreeSet<String>,
+    responses: &mut BTreeMap<ResponseKey, CertifiedResponse>,
+) {
+    // (url, whether it's a wildcard, status, headers)
+    let mut served: Vec<(String, bool, StatusCode, Vec<HeaderField>)> = vec![];
+    let mut add = |url: &str, wildcard, response: &HttpResponse| {
+        let headers = response.headers().to_vec();
+        served.push((url.to_string(), wildcard, response.status_code(), headers));
+    };
+    for url in certified_urls() {
+        for (encoding, range) in ENCODINGS.iter().flat_map(|e| [(e, None), (e, Some(0))]) {
+            if let Some(response) = asset_router
+                .get_assets()
+                .get(url.as_str(), *encoding, range)
+            {
+                add(&url, false, response);
+            }
+        }
+    }
+    for scope in scopes {
+        for (encoding, range) in ENCODINGS.iter().flat_map(|e| [(e, None), (e, Some(0))]) {
+            let fallbacks = asset_router.get_fallback_assets();
+            if let Some(response) = fallbacks.get(scope.as_str(), *encoding, range) {
+                add(scope, true, response);
+            }
+        }
+    }
+    ENCODING_OVERRIDES.with_borrow(|overrides| {
+        for ((url, _), certified) in overrides {
+            add(url, false, &certified.response);
+        }
+    });
+    for ((kind, url, wildcard, _), certified) in responses.iter() {
+        if *kind != ResponseKind::NotModified {
+            add(url, *wildcard, &certified.response);
+        }
+    }
+
+    for (url, wildcard, status_code, headers) in served {
+        let path = if wildcard {
+            HttpCertificationPath::wildcard(url.clone())
+        } else {
+            HttpCertificationPath::exact(url.clone())
+        };
+        let request = HttpRequest::builder()
+            .with_method(Method::HEAD)
+            .with_url(url.clone())
+            .build();
+        let variant = head_variant(status_code, &headers);
+        let certified = certified_response(path, &request, &[], status_code, headers, vec![]);
+        responses.insert((ResponseKind::Head, url, wildcard, variant), certified);
+    }
+}
+
+// The base path and the scopes of the router's fallbacks under it
+fn custom_scopes(asset_router: &AssetRouter<'static>) -> BTreeSet<String> {
+    let base_path = base_path();
+    let configured =
+        config::with_config(|config| config.fallbacks.keys().cloned().collect::<Vec<_>>());
+    let mut scopes: BTreeSet<String> = configured
+        .iter()
+        .map(|scope| under_base_path(&base_path, scope))
+        .filter(|scope| {
+            asset_router
+                .get_fallback_assets()
+                .get(scope.as_str(), None, None)
+                .is_some()
+        })
+        .collect();
+    scopes.insert(under_base_path(&base_path, "/"));
+    scopes
+}
+
+// Certifies the page for GET requests of the URL, or of any under it for a wildcard, with
+// its length like the router's responses
+fn certify_page(
+    responses: &mut BTreeMap<ResponseKey, CertifiedResponse>,
+    kind: ResponseKind,
+    variant: &str,
+    url: &str,
+    wildcard: bool,
+    page: HttpResponse<'static>,
+) {
+    let path = if wildcard {
+        HttpCertificationPath::wildcard(url.to_string())
+    } else {
+        HttpCertificationPath::exact(url.to_string())
+    };
+    let mut headers = vec![("content-length".to_string(), page.body().len().to_string())];
+    headers.extend(page.headers().iter().cloned());
+    let certified = certified_response(
+        path,
+        &HttpRequest::get(url.to_string()).build(),
+        &[],
+        page.status_code(),
+        headers,
+        page.body().to_vec(),
+    );
+    responses.insert(
+        (kind, url.to_string(), wildcard, variant.to_string()),
+        certified,
+    );
+}
+
+// Paths of the certified assets and redirects, without the leading slash
+fn certified_paths() -> Vec<String> {
+    let mut paths: Vec<String> = STATIC_PATHS.with_borrow(|paths| paths.iter().cloned().collect());
+    paths.extend(DYNAMIC_ASSETS.with_borrow(|certified| {
+        certified
+            .assets
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>()
+    }));
+    paths
+}
+
+// The languages of the certified index.{lang}.html pages
+fn certified_languages() -> Vec<String> {
+    certified_paths()
+        .iter()
+        .filter_map(|path| path.strip_prefix("index.")?.strip_suffix(".html"))
+        .filter(|language| !language.is_empty())
+        .filter(|language| language.chars().all(|c| c.is_ascii_lowercase()))
+        .map(String::from)
+        .collect()
+}
+
+// The URLs of the certified assets and their aliases, under the base path
+fn certified_urls() -> BTreeSet<String> {
+    let base_path = base_path();
+    let mut paths: Vec<String> = certified_paths()
+        .iter()
+        .map(|path| format!("/{}", path))
+        .collect();
+    paths.push("/".to_string());
+    paths.extend(
+        certified_languages()
+            .iter()
+            .map(|language| format!("/{}", language)),
+    );
+    paths.extend(config::with_config(|config| {
+        config.aliases.keys().cloned().collect::<Vec<_>>()
+    }));
+    paths
+        .iter()
+        .map(|path| under_base_path(&base_path, path))
+        .collect()
+}
+
+// The paths serves_status is true for, other than client-side routes, relative to the
+// base path. Only those the router has an asset for are certified.
+fn status_paths() -> Vec<String> {
+    let mut paths: Vec<String> = [
+        "/",
+        "/index.html",
+        "/metrics",
+        "/version.json",
+        "/api/status.json",
+        "/api/status-stream.json",
+    ]
+    .map(String::from)
+    .into();
+    for language in certified_languages() {
+        paths.push(format!("/index.{}.html", language));
+        paths.push(format!("/{}", language));
+    }
+    let aliases = config::with_config(|config| config.aliases.clone());
+    let aliased: Vec<String> = aliases
+        .into_iter()
+        .filter(|(_, target)| paths.contains(&format!("/{}", target.trim_start_matches('/'))))
+        .map(|(alias, _)| alias)
+        .collect();
+    paths.extend(aliased);
+    paths
+}
+
+// The wildcard scopes a response verifier accepts for a URL, the most specific first. One
+// certified at a scope is only valid while no more specific one is in the tree.
+fn wildcard_scopes(url: &str) -> Vec<String> {
+    let mut segments: Vec<&str> = url.split('/').filter(|s| !s.is_empty()).collect();
+    let mut scopes = vec![];
+    while let Some(last) = segments.pop() {
+        let parent = format!("/{}", segments.join("/"));
+        scopes.push(format!("{}/{}", parent.trim_end_matches('/'), last));
+        scopes.push(if segments.is_empty() {
+            parent
+        } else {
+            format!("{}/", parent)
+        });
+    }
+    if scopes.is_empty() {
+        scopes.push("/".to_string());
+    }
+    scopes
+}
+
+// The certified response of the kind for the request, the one at its exact path or else
+// the one of the most specific scope
+fn serve_custom(
+    req: &HttpRequest,
+    kind: ResponseKind,
+    variant: &str,
+    data_certificate: &[u8],
+) -> Option<HttpResponse<'static>> {
+    let url = req.get_path().ok()?;
+    CUSTOM_RESPONSES.with_borrow(|responses| {
+        let exact = (kind, url.clone(), false, variant.to_string());
+        responses
+            .get(&exact)
+            .or_else(|| {
+                wildcard_scopes(&url)
+                    .into_iter()
+                    .find_map(|scope| responses.get(&(kind, scope, true, variant.to_string())))
+            })
+            .and_then(|certified| with_certificate(certified, &url, data_certificate))
+    })
+}
+
+// The expression the router certifies its responses with, certifying the given request
+// headers along with the method and body
+fn full_cel_expr<'a>(request_headers: &'a [&'a str]) -> DefaultFullCelExpression<'a> {
+    DefaultCelBuilder::full_certification()
+        .with_request_headers(request_headers)
+        .with_response_certification(DefaultResponseCertification::response_header_exclusions(
+            vec![],
+        ))
+        .build()
+}
+
+// Certifies the response for the request at the path, under full_cel_expr with its
+// expression header replacing any the headers carry, so it verifies like the router's
+// responses do. The headers come in the order they're served in.
+fn certified_response(
+    path: HttpCertificationPath<'static>,
+    request: &HttpRequest,
+    request_headers: &[&str],
+    status_code: StatusCode,
+    headers: Vec<HeaderField>,
+    body: Vec<u8>,
+) -> CertifiedResponse {
+    let cel_expr = full_cel_expr(request_headers);
+    let mut headers: Vec<HeaderField> = headers
+        .into_iter()
+        .filter(|(name, _)| !name.eq_ignore_ascii_case(CERTIFICATE_EXPRESSION_HEADER_NAME))
+        .collect();
+    headers.push((
+        CERTIFICATE_EXPRESSION_HEADER_NAME.to_string(),
+        cel_expr.to_string(),
+    ));
+    let response = HttpResponse::builder()
+        .with_status_code(status_code)
+        .with_headers(headers)
+        .with_body(body)
+        .build();
+    let certification = HttpCertification::full(&cel_expr, request, &response, None)
+        .unwrap_or_else(|err| {
+            ic_cdk::trap(&format!("Failed to certify {}: {}", request.url(), err))
+        });
+    CertifiedResponse {
+        response,
+        entry: HttpCertificationTreeEntry::new(path, certification),
+    }
+}
+
+// The certified response with the certificate header for the URL, like the router adds
+fn with_certificate(
+    certified: &CertifiedResponse,
+    url: &str,
+    data_certificate: &[u8],
+) -> Option<HttpResponse<'static>> {
+    let witness = HTTP_TREE
+        .with(|tree| tree.borrow().witness(&certified.entry, url))
+        .ok()?;
+    let mut response = certified.response.clone();
+    add_v2_certificate_header(
+        data_certificate,
+        &mut response,
+        &witness,
+        &certified.entry.path.to_expr_path(),
+    );
+    Some(response)
+}
+
+// Swaps in the certified variant with the cache-control of the encoding the router picked
+fn with_encoding_override(
+    req: &HttpRequest,
+    response: HttpResponse<'static>,
+    data_certificate: &[u8],
+) -> HttpResponse<'static> {
+    let (Some(encoding), Ok(url)) = (
+        get_header(response.headers(), "content-encoding"),
+        req.get_path(),
+    ) else {
+        return response;
+    };
+    let key = (url.clone(), encoding.to_string());
+    let overridden = ENCODING_OVERRIDES.with_borrow(|overrides| {
+        overrides
+            .get(&key)
+            .and_then(|certified| with_certificate(certified, &url, data_certificate))
+    });
+    overridden.unwrap_or(response)
+}
+
+fn base_path() -> String {
+    config::with_config(|config| config.base_path.clone())
+}
+
+// Everything is certified under the base path, so responses verify for the prefixed
+// URLs the gateway requests. Asset paths and patterns are relative, the rest absolute.
+fn under_base_path(base_path: &str, path: &str) -> String {
+    if base_path.is_empty() {
+        return path.to_string();
+    }
+    match path.strip_prefix('/') {
+        Some("") => base_path.to_string(),
+        Some(path) => format!("{}/{}", base_path, path),
+        None => format!("{}/{}", base_path.trim_start_matches('/'), path),
+    }
+}
+
+fn config_under_base_path(base_path: &str, config: AssetConfig) -> AssetConfig {
+    match config {
+        AssetConfig::File {
+            path,
+            content_type,
+            headers,
+            fallback_for,
+            aliased_by,
+            encodings,
+        } => AssetConfig::File {
+            path: under_base_path(base_path, &path),
+            content_type,
+            headers,
+            fallback_for: fallback_for
+                .into_iter()
+                .map(|fallback| AssetFallbackConfig {
+                    scope: under_base_path(base_path, &fallback.scope),
+                    status_code: fallback.status_code,
+                })
+                .collect(),
+            aliased_by: aliased_by
+                .iter()
+                .map(|alias| under_base_path(base_path, alias))
+                .collect(),
+            encodings,
+        },
+        AssetConfig::Pattern {
+            pattern,
+            content_type,
+            headers,
+            encodings,
+        } => AssetConfig::Pattern {
+            pattern: under_base_path(base_path, &pattern),
+            content_type,
+            headers,
+            encodings,
+        },
+        AssetConfig::Redirect {
+            from,
+            to,
+            kind,
+            headers,
+        } => AssetConfig::Redirect {
+            from: under_base_path(base_path, &from),
+            // External targets are left as they are
+            to: if to.starts_with('/') {
+                under_base_path(base_path, &to)
+            } else {
+                to
+            },
+            kind,
+            headers,
+        },
+    }
+}
+
+// The request path relative to the base path, None when it's outside of it
+fn local_path(req: &HttpRequest) -> Option<String> {
+    let path = req.get_path().ok()?;
+    let base_path = base_path();
+    match path.strip_prefix(base_path.as_str())? {
+        "" => Some("/".to_string()),
+        local if local.starts_with('/') => Some(local.to_string()),
+        _ => None,
+    }
+}
+
+// An empty directory still builds, so explain the blank site instead
+fn add_missing_assets_placeholder(
+    dir: &Dir<'_>,
+    static_assets: &mut AssetContents,
+    dynamic_assets: &AssetContents,
+) {
+    if dir.entries().is_empty()
+        && !static_assets
+            .iter()
+            .chain(dynamic_assets)
+            .any(|(path, _)| path == "index.html")
+    {
+        crate::log("No bundled assets found, serving a placeholder at /");
+        static_assets.push(("index.html".to_string(), Cow::Borrowed(MISSING_ASSETS_HTML)));
+    }
+}
+
+fn static_assets() -> Vec<(String, Cow<'static, [u8]>)> {
+    let mut assets = Vec::new();
+    for file in ASSETS_DIR.files() {
+        let path = file.path().to_string_lossy();
+        // Special case for templating
+        if let Some(name) = path.strip_suffix(".hbs") {
+            if !ENABLE_TEMPLATING {
+                assets.push((format!("{}.html", name), Cow::Borrowed(file.contents())));
+            }
+        } else {
+            assets.push((path.to_string(), Cow::Borrowed(file.contents())));
+        }
+    }
+    // Nested directories aren't served, apart from .well-known for ic-domains and the
+    // like. include_dir! keeps directories with a leading dot.
+    if let Some(dir) = ASSETS_DIR.get_dir(".well-known") {
+        assets.extend(dir.files().map(|file| {
+            (
+                file.path().to_string_lossy().to_string(),
+                Cow::Borrowed(file.contents()),
+            )
+        }));
+    }
+    // Uploaded assets take precedence over bundled ones at the same path
+    upload::with_uploaded_assets(|uploaded| {
+        assets.retain(|(path, _)| !uploaded.contains_key(path));
+        assets.extend(
+            uploaded
+                .iter()
+                .map(|(path, asset)| (path, Cow::Owned(asset.content))),
+        );
+    });
+    if !assets.iter().any(|(path, _)| path == "favicon.ico") {
+        assets.push(("favicon.ico".to_string(), Cow::Borrowed(DEFAULT_FAVICON)));
+    }
+    if !assets.iter().any(|(path, _)| path == "robots.txt") {
+        assets.push(("robots.txt".to_string(), Cow::Borrowed(DEFAULT_ROBOTS_TXT)));
+    }
+    if !assets.iter().any(|(path, _)| path == SECURITY_TXT_PATH) {
+        if let Some(security_txt) = security_txt() {
+            assets.push((
+                SECURITY_TXT_PATH.to_string(),
+                Cow::Owned(security_txt.into_bytes()),
+            ));
+        }
+    }
+    // Liveness probe, independent of the management canister
+    assets.push(("healthz".to_string(), Cow::Borrowed(b"ok")));
+    assets
+}
+
+async fn dynamic_assets() -> Result<Vec<(String, Cow<'static, [u8]>)>, String> {
+    if !ENABLE_TEMPLATING {
+        return Ok(vec![]);
+    }
+
+    let started = ic_cdk::api::performance_counter(1);
+    let status = fetch_canister_status().await?;
+    let fetched = ic_cdk::api::performance_counter(1);
+    let (templates, partials) = template_files();
+    let mut assets: Vec<(String, Cow<'static, [u8]>)> =
+        serve_canister_info(&templates, &partials, &status)
+            .into_iter()
+            .map(|(name, rendered)| (format!("{}.html", name), Cow::Owned(rendered.into_bytes())))
+            .collect();
+
+    let metrics = Cow::Owned(render_metrics(&status).into_bytes());
+    assets.push(("metrics".to_string(), metrics));
+    let json = serde_json::to_vec(&status).unwrap_or_default();
+    let content_hash = status_content_hash(&status);
+    assets.push(("api/status.json".to_string(), Cow::Owned(json)));
+    let stream = StatusStream {
+        revision: STATUS_REVISION.with_borrow_mut(|(revision, hash)| {
+            if *hash != content_hash {
+                *revision += 1;
+                *hash = content_hash;
+            }
+            *revision
+        }),
+        next_poll_after_secs: config::with_config(|config| config.update_interval_secs),
+        status: &status,
+    };
+    let stream = Cow::Owned(serde_json::to_vec(&stream).unwrap_or_default());
+    assets.push(("api/status-stream.json".to_string(), stream));
+    let build_info = build_info_with(Some(status.module_hash.clone()));
+    let version = Cow::Owned(serde_json::to_vec(&build_info).unwrap_or_default());
+    assets.push(("version.json".to_string(), version));
+    let rendered = ic_cdk::api::performance_counter(1);
+    record_phase_costs(
+        fetched.saturating_sub(started),
+        rendered.saturating_sub(fetched),
+    );
+    Ok(assets)
+}
+
+// Leaves out the time of the run and what it cost, which change on every run even when
+// nothing else does
+fn status_content_hash(status: &DefiniteCanisterStatus) -> [u8; 32] {
+    let mut status = status.clone();
+    status.last_updated_at.clear();
+    status.last_certified_at.clear();
+    status.last_cycles_cost = 0;
+    status.certification_duration_ms = 0;
+    status.cost_breakdown = Default::default();
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_vec(&status).unwrap_or_default());
+    hasher.update(config::with_config(serde_json::to_vec).unwrap_or_default());
+    hasher.finalize().into()
+}
+
+// Configured redirects, and the aliases redirecting with a status the router certifies.
+// Those with any other status are certified by certify_custom_responses.
+fn redirect_configs() -> Vec<AssetConfig> {
+    config::with_config(|config| {
+        let aliases = config
+            .alias_redirects
+            .iter()
+            .filter_map(|(alias, (target, status))| {
+                let kind = match *status {
+                    301 => true,
+                    307 => false,
+                    _ => return None,
+                };
+                Some((alias, (target, kind)))
+            });
+        config
+            .redirects
+            .iter()
+            .map(|(from, (to, permanent))| (from, (to, *permanent)))
+            .chain(aliases)
+            .map(|(from, (to, permanent))| AssetConfig::Redirect {
+                from: from.clone(),
+                to: to.clone(),
+                kind: if permanent {
+                    AssetRedirectKind::Permanent
+                } else {
+                    AssetRedirectKind::Temporary
+                },
+                headers: get_asset_headers(vec![]),
+            })
+            .collect()
+    })
+}
+
+type TemplateFiles = Vec<(String, &'static File<'static>)>;
+
+// Partials are only available to templates and aren't served on their own
+pub(crate) fn template_files() -> (TemplateFiles, TemplateFiles) {
+    let templates = ASSETS_DIR.files().filter_map(template_name).collect();
+    let partials = ASSETS_DIR
+        .get_dir("partials")
+        .map(|dir| dir.files().filter_map(template_name).collect())
+        .unwrap_or_default();
+    (templates, partials)
+}
+
+// Templates are named by their path without the extension, partials by their file stem
+fn template_name(file: &'static File<'static>) -> Option<(String, &'static File<'static>)> {
+    let path = file.path();
+    if path.extension()? != "hbs" {
+        return None;
+    }
+    let name = if path.starts_with("partials") {
+        path.file_stem()?.to_string_lossy().to_string()
+    } else {
+        path.with_extension("").to_string_lossy().to_string()
+    };
+    Some((name, file))
+}
+
+// The router serves the first of these the client accepts
+fn configured_encodings() -> Vec<(AssetEncoding, String)> {
+    config::with_config(|config| {
+        let mut encodings: Vec<AssetEncoding> = config
+            .encodings
+            .iter()
+            .filter_map(|name| config::parse_encoding(name))
+            .collect();
+        if config.prefer_gzip {
+            encodings.sort_by_key(|encoding| !matches!(encoding, AssetEncoding::Gzip));
+        }
+        encodings
+            .into_iter()
+            .map(|encoding| encoding.default_config())
+            .collect()
+    })
+}
+
+// Uploads can be replaced at any time, so they're never cached as immutable
+fn uploaded_asset_configs() -> Vec<AssetConfig> {
+    let encodings = configured_encodings();
+    upload::with_uploaded_assets(|uploaded| {
+        uploaded
+            .iter()
+            .map(|(path, asset)| AssetConfig::File {
+                path,
+                content_type: Some(asset.content_type),
+                headers: get_asset_headers(vec![(
+                    "cache-control".to_string(),
+                    NO_CACHE_CONTROL.to_string(),
+                )]),
+                fallback_for: vec![],
+                aliased_by: vec![],
+                encodings: encodings.clone(),
+            })
+            .collect()
+    })
+}
+
+fn maintenance_config(retry_after_secs: u32) -> AssetConfig {
+    AssetConfig::File {
+        path: "maintenance.html".to_string(),
+        content_type: Some("text/html".to_string()),
+        headers: get_asset_headers(vec![
+            ("cache-control".to_string(), NO_CACHE_CONTROL.to_string()),
+            ("retry-after".to_string(), retry_after_secs.to_string()),
+        ]),
+        fallback_for: vec![AssetFallbackConfig {
+            scope: "/".to_string(),
+            status_code: Some(StatusCode::SERVICE_UNAVAILABLE),
+        }],
+        aliased_by: vec![],
+        encodings: configured_encodings(),
+    }
+}
+
+fn asset_configs() -> Vec<AssetConfig> {
+    let encodings = configured_encodings();
+    let immutable = immutable_cache_control();
+    let html_cache_control = config::with_config(|config| config.html_cache_control.clone());
+    // Client-side routes get index.html instead of the 404 page
+    let spa_mode = config::with_config(|config| config.spa_mode);
+    let fallback = |status_code| {
+        vec![AssetFallbackConfig {
+            scope: "/".to_string(),
+            status_code: Some(status_code),
+        }]
+    };
+
+    let mut configs = vec![
+        AssetConfig::File {
+            path: "index.html".to_string(),
+            content_type: Some("text/html".to_string()),
+            headers: get_asset_headers(vec![(
+                "cache-control".to_string(),
+                html_cache_control.clone(),
+            )]),
+            fallback_for: if spa_mode {
+                fallback(StatusCode::OK)
+            } else {
+                vec![]
+            },
+            aliased_by: vec!["/".to_string()],
+            encodings: encodings.clone(),
+        },
+        AssetConfig::File {
+            path: "404.html".to_string(),
+            content_type: Some("text/html".to_string()),
+            headers: get_asset_headers(vec![(
+                "cache-control".to_string(),
+                html_cache_control.clone(),
+            )]),
+            // The certified fallback for / covers every nested path without a more
+            // specific asset, e.g. /a/b/does-not-exist gets this body with a 404
+            fallback_for: if spa_mode {
+                vec![]
+            } else {
+                fallback(StatusCode::NOT_FOUND)
+            },
+            aliased_by: vec![],
+            encodings: encodings.clone(),
+        },
+        dynamic_asset_config("healthz", "text/plain", &[]),
+        // Not cached so they can be updated with an upload
+        dynamic_asset_config("robots.txt", "text/plain", &encodings),
+        dynamic_asset_config("sitemap.xml", "application/xml", &encodings),
+        dynamic_asset_config("metrics", "text/plain; version=0.0.4", &encodings),
+        dynamic_asset_config("api/status.json", "application/json", &encodings),
+        dynamic_asset_config("api/status-stream.json", "application/json", &encodings),
+        dynamic_asset_config("version.json", "application/json", &encodings),
+        pattern_config(".well-known/*", "text/plain", NO_CACHE_CONTROL, &encodings),
+        pattern_config("**/*.js", "text/javascript", &immutable, &encodings),
+        pattern_config("**/*.css", "text/css", &immutable, &encodings),
+        pattern_config("**/*.svg", "image/svg+xml", &immutable, &encodings),
+        pattern_config(
+            "**/*.json",
+            "application/json",
+            NO_CACHE_CONTROL,
+            &encodings,
+        ),
+        pattern_config("**/*.wasm", "application/wasm", &immutable, &encodings),
+        pattern_config("**/*.woff2", "font/woff2", &immutable, &encodings),
+        pattern_config("**/*.ico", "image/x-icon", &immutable, &encodings),
+    ];
+    configs.extend(language_configs(&html_cache_control, &encodings));
+    add_configured_fallbacks(&mut configs, &html_cache_control, &encodings);
+    configs
+}
+
+// Pages that already have a config, such as 404.html, get the extra scopes added to it
+fn add_configured_fallbacks(
+    configs: &mut Vec<AssetConfig>,
+    cache_control: &str,
+    encodings: &[(AssetEncoding, String)],
+) {
+    let fallbacks = config::with_config(|config| config.fallbacks.clone());
+    for (scope, (page_path, status)) in fallbacks {
+        let fallback = AssetFallbackConfig {
+            scope,
+            status_code: StatusCode::from_u16(status).ok(),
+        };
+        let existing = configs.iter_mut().find_map(|config| match config {
+            AssetConfig::File {
+                path, fallback_for, ..
+            } if *path == page_path => Some(fallback_for),
+            _ => None,
+        });
+        match existing {
+            Some(fallback_for) => fallback_for.push(fallback),
+            None => configs.push(AssetConfig::File {
+                path: page_path,
+                content_type: Some("text/html".to_string()),
+                headers: get_asset_headers(vec![(
+                    "cache-control".to_string(),
+                    cache_control.to_string(),
+                )]),
+                fallback_for: vec![fallback],
+                aliased_by: vec![],
+                encodings: encodings.to_vec(),
+            }),
+        }
+    }
+}
+
+// Each index.{lang}.hbs is rendered to index.{lang}.html and served at /{lang}
+fn language_configs(
+    cache_control: &str,
+    encodings: &[(AssetEncoding, String)],
+) -> Vec<AssetConfig> {
+    template_files()
+        .0
+        .into_iter()
+        .filter_map(|(name, _)| {
+            let language = name.strip_prefix("index.")?;
+            (!language.is_empty() && language.chars().all(|c| c.is_ascii_lowercase())).then(|| {
+                AssetConfig::File {
+                    path: format!("{}.html", name),
+                    content_type: Some("text/html".to_string()),
+                    headers: get_asset_headers(vec![(
+                        "cache-control".to_string(),
+                        cache_control.to_string(),
+                    )]),
+                    fallback_for: vec![],
+                    aliased_by: vec![format!("/{}", language)],
+                    encodings: encodings.to_vec(),
+                }
+            })
+        })
+        .collect()
+}
+
+// Pins every asset to its own file config, inheriting from the first config that
+// matches it, so that per-content headers such as the ETag are certified too.
+fn specialize_asset_configs(
+    asset_configs: &[AssetConfig],
+    assets: &[(String, Cow<'static, [u8]>)],
+    encoded: &[(String, Cow<'static, [u8]>)],
+    modified_at: &BTreeMap<String, u64>,
+) -> Vec<AssetConfig> {
+    let mut specialized = Vec::new();
+    for (path, content) in assets {
+        let Some(config) = asset_configs
+            .iter()
+            .find(|config| config_matches(config, path))
+        else {
+            continue;
+        };
+
+        // Validators, sent back as is they get the 304 certify_custom_responses certified
+        // for them
+        let mut extra_headers = vec![("etag".to_string(), etag(content))];
+        // The router adds the content-length of each encoding and chunk it certifies
+        if has_encoded_variants(path, config, encoded) {
+            extra_headers.push(("vary".to_string(), "accept-encoding".to_string()));
+        }
+        if let Some(modified_at) = modified_at.get(path) {
+            extra_headers.push(("last-modified".to_string(), http_date(*modified_at)));
+        }
+        extra_headers.extend(header_overrides(path));
+        let aliases = configured_aliases(path);
+        match config {
+            AssetConfig::File {
+                content_type,
+                headers,
+                fallback_for,
+                aliased_by,
+                encodings,
+                ..
+            } => specialized.push(AssetConfig::File {
+                path: path.clone(),
+                content_type: content_type.clone(),
+                headers: without_excluded_headers(path, [headers.clone(), extra_headers].concat()),
+                fallback_for: fallback_for.clone(),
+                aliased_by: [aliased_by.clone(), aliases].concat(),
+                encodings: encodings.clone(),
+            }),
+            AssetConfig::Pattern {
+                content_type,
+                headers,
+                encodings,
+                ..
+            } => specialized.push(AssetConfig::File {
+                path: path.clone(),
+                content_type: content_type.clone(),
+                headers: without_excluded_headers(path, [headers.clone(), extra_headers].concat()),
+                fallback_for: vec![],
+                aliased_by: aliases,
+                encodings: encodings.clone(),
+            }),
+            _ => {}
+        }
+    }
+
+    specialized
+}
+
+fn has_encoded_variants(
+    path: &str,
+    config: &AssetConfig,
+    encoded: &[(String, Cow<'static, [u8]>)],
+) -> bool {
+    config_encodings(config).iter().any(|(_, extension)| {
+        let encoded_path = format!("{}{}", path, extension);
+        encoded
+            .iter()
+            .any(|(asset_path, _)| *asset_path == encoded_path)
+    })
+}
+
+fn config_encodings(config: &AssetConfig) -> &[(AssetEncoding, String)] {
+    match config {
+        AssetConfig::File { encodings, .. } | AssetConfig::Pattern { encodings, .. } => encodings,
+        _ => &[],
+    }
+}
+
+type AssetContents = Vec<(String, Cow<'static, [u8]>)>;
+
+fn encoding_extensions() -> [String; 3] {
+    [
+        AssetEncoding::Brotli,
+        AssetEncoding::Gzip,
+        AssetEncoding::Deflate,
+    ]
+    .map(|encoding| encoding.default_config().1)
+}
+
+// Separates files like app.js.br that a build emitted next to app.js, so they're served
+// as that encoding of the base asset instead of on their own
+fn split_precompressed(assets: AssetContents) -> (AssetContents, AssetContents) {
+    let extensions = encoding_extensions();
+    let paths: BTreeSet<String> = assets.iter().map(|(path, _)| path.clone()).collect();
+    assets.into_iter().partition(|(path, _)| {
+        !extensions.iter().any(|extension| {
+            path.strip_suffix(extension.as_str())
+                .is_some_and(|base| paths.contains(base))
+        })
+    })
+}
+
+// The router only serves encodings that are certified as separate assets, so every
+// asset is compressed up front unless a precompressed file was bundled. Higher levels
+// cost more instructions per certification.
+fn encode_assets(
+    asset_configs: &[AssetConfig],
+    assets: &[(String, Cow<'static, [u8]>)],
+    precompressed: &[(String, Cow<'static, [u8]>)],
+) -> Vec<(String, Cow<'static, [u8]>)> {
+    let (gzip_level, brotli_quality) =
+        config::with_config(|config| (config.gzip_level, config.brotli_quality));
+    let mut encoded = Vec::new();
+    for (path, content) in assets {
+        let Some(config) = asset_configs
+            .iter()
+            .find(|config| config_matches(config, path))
+        else {
+            continue;
+        };
+        for (encoding, extension) in config_encodings(config) {
+            let encoded_path = format!("{}{}", path, extension);
+            if let Some(bundled) = precompressed
+                .iter()
+                .find(|(bundled_path, _)| *bundled_path == encoded_path)
+            {
+                encoded.push(bundled.clone());
+                continue;
+            }
+            let compressed = match encoding {
+                AssetEncoding::Brotli => compress_brotli(content, brotli_quality),
+                AssetEncoding::Gzip => compress_gzip(content, gzip_level),
+                AssetEncoding::Deflate => compress_deflate(content, gzip_level),
+                _ => None,
+            };
+            // Already compressed formats don't shrink any further
+            if let Some(compressed) = compressed.filter(|bytes| bytes.len() < content.len()) {
+                encoded.push((encoded_path, Cow::Owned(compressed)));
+            }
+        }
+    }
+    encoded
+}
+
+fn compress_brotli(content: &[u8], quality: u8) -> Option<Vec<u8>> {
+    let params = BrotliEncoderParams {
+        quality: i32::from(quality),
+        ..Default::default()
+    };
+    let mut compressed = Vec::new();
+    brotli::BrotliCompress(&mut &content[..], &mut compressed, &params).ok()?;
+    Some(compressed)
+}
+
+fn compress_gzip(content: &[u8], level: u8) -> Option<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::new(u32::from(level)));
+    encoder.write_all(content).ok()?;
+    encoder.finish().ok()
+}
+
+fn compress_deflate(content: &[u8], level: u8) -> Option<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(u32::from(level)));
+    encoder.write_all(content).ok()?;
+    encoder.finish().ok()
+}
+
+fn configured_aliases(path: &str) -> Vec<String> {
+    config::with_config(|config| {
+        config
+            .aliases
+            .iter()
+            .filter(|(_, target)| target.trim_start_matches('/') == path)
+            .map(|(alias, _)| alias.clone())
+            .collect()
+    })
+}
+
+fn header_overrides(path: &str) -> Vec<HeaderField> {
+    config::with_config(|config| {
+        let mut headers: Vec<HeaderField> = config
+            .asset_headers
+            .iter()
+            .filter(|(pattern, _)| glob_matches(pattern, path))
+            .flat_map(|(_, headers)| headers.iter().cloned())
+            .collect();
+        let is_download = config
+            .download_patterns
+            .iter()
+            .any(|pattern| glob_matches(pattern, path));
+        if is_download && get_header(&headers, "content-disposition").is_none() {
+            headers.push(("content-disposition".to_string(), content_disposition(path)));
+        }
+        headers
+    })
+}
+
+// Assets matching a header exclusion, e.g. an embeddable widget, drop the cross-origin
+// isolation headers
+fn without_excluded_headers(path: &str, headers: Vec<HeaderField>) -> Vec<HeaderField> {
+    let excluded = config::with_config(|config| {
+        config
+            .header_exclusions
+            .iter()
+            .any(|pattern| glob_matches(pattern, path))
+    });
+    if !excluded {
+        return headers;
+    }
+    headers
+        .into_iter()
+        .filter(|(name, _)| {
+            !name.eq_ignore_ascii_case("cross-origin-embedder-policy")
+                && !name.eq_ignore_ascii_case("cross-origin-opener-policy")
+        })
+        .collect()
+}
+
+// Characters that can't appear in a quoted filename are replaced
+fn content_disposition(path: &str) -> String {
+    let name: String = path
+        .rsplit('/')
+        .next()
+        .unwrap_or(path)
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    format!("attachment; filename=\"{}\"", name)
+}
+
+fn glob_matches(pattern: &str, path: &str) -> bool {
+    Glob::new(pattern)
+        .map(|glob| glob.compile_matcher().is_match(path))
+        .unwrap_or(false)
+}
+
+fn config_matches(config: &AssetConfig, path: &str) -> bool {
+    match config {
+        AssetConfig::File {
+            path: config_path, ..
+        } => config_path == path,
+        AssetConfig::Pattern { pattern, .. } => glob_matches(pattern, path),
+        _ => false,
+    }
+}
+
+// Weak, as every encoding of an asset is served with the hash of the identity body
+fn etag(content: &[u8]) -> String {
+    format!("W/\"{}\"", hex::encode(Sha256::digest(content)))
+}
+
+fn immutable_cache_control() -> String {
+    let max_age = config::with_config(|config| config.immutable_max_age_secs);
+    if max_age >= IMMUTABLE_MIN_MAX_AGE_SECS {
+        format!("public, max-age={}, immutable", max_age)
+    } else {
+        format!("public, max-age={}", max_age)
+    }
+}
+
+fn pattern_config(
+    pattern: &str,
+    content_type: &str,
+    cache_control: &str,
+    encodings: &[(AssetEncoding, String)],
+) -> AssetConfig {
+    AssetConfig::Pattern {
+        pattern: pattern.to_string(),
+        content_type: Some(content_type.to_string()),
+        headers: get_asset_headers(vec![(
+            "cache-control".to_string(),
+            cache_control.to_string(),
+        )]),
+        encodings: encodings.to_vec(),
+    }
+}
+
+// Assets that must always be fetched fresh
+fn dynamic_asset_config(
+    path: &str,
+    content_type: &str,
+    encodings: &[(AssetEncoding, String)],
+) -> AssetConfig {
+    AssetConfig::File {
+        path: path.to_string(),
+        content_type: Some(content_type.to_string()),
+        headers: get_asset_headers(vec![(
+            "cache-control".to_string(),
+            NO_CACHE_CONTROL.to_string(),
+        )]),
+        fallback_for: vec![],
+        aliased_by: vec![],
+        encodings: encodings.to_vec(),
+    }
+}
+
+fn hsts_header() -> String {
+    config::with_config(|config| {
+        let mut value = format!("max-age={}", config.hsts_max_age_secs);
+        if config.hsts_include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if config.hsts_preload {
+            value.push_str("; preload");
+        }
+        value
+    })
+}
+
+fn get_asset_headers(additional_headers: Vec<HeaderField>) -> Vec<HeaderField> {
+    let mut headers = vec![
+        ("strict-transport-security".to_string(), hsts_header()),
+        ("x-content-type-options".to_string(), "nosniff".to_string()),
+        ("accept-ranges".to_string(), "bytes".to_string()),
+        (
+            "content-security-policy".to_string(),
+            content_security_policy(),
+        ),
+        ("referrer-policy".to_string(), "no-referrer".to_string()),
+        (
+            "permissions-policy".to_string(),
+            config::with_config(|config| config.permissions_policy.clone()),
+        ),
+        (
+            "cross-origin-embedder-policy".to_string(),
+            "require-corp".to_string(),
+        ),
+        (
+            "cross-origin-opener-policy".to_string(),
+            "same-origin".to_string(),
+        ),
+    ];
+    if let Some(x_frame_options) = x_frame_options() {
+        headers.push(("x-frame-options".to_string(), x_frame_options.to_string()));
+    }
+    if config::with_config(|config| config.debug_headers) {
+        headers.push((
+            "x-ic-certification-version".to_string(),
+            CERTIFICATION_VERSION.to_string(),
+        ));
+    }
+    headers.extend(crate::cors::certified_headers());
+    headers.extend(additional_headers);
+    headers
+}
+
+// The configured policy with its frame-ancestors directive replaced by the configured
+// ancestors
+fn content_security_policy() -> String {
+    config::with_config(|config| {
+        let frame_ancestors = if config.frame_ancestors.is_empty() {
+            "frame-ancestors 'none'".to_string()
+        } else {
+            format!("frame-ancestors {}", config.frame_ancestors.join(" "))
+        };
+        let mut directives: Vec<&str> = config
+            .content_security_policy
+            .split(';')
+            .map(str::trim)
+            .filter(|directive| {
+                !directive.is_empty()
+                    && !directive
+                        .to_ascii_lowercase()
+                        .starts_with("frame-ancestors")
+            })
+            .collect();
+        directives.push(&frame_ancestors);
+        directives.join("; ")
+    })
+}
+
+fn x_frame_options() -> Option<&'static str> {
+    config::with_config(|config| match config.frame_ancestors.as_slice() {
+        [] => Some("DENY"),
+        [ancestor] if ancestor == "'self'" => Some("SAMEORIGIN"),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use ic_certification::{LookupResult, SubtreeLookupResult};
+    use ic_http_certification::request_hash;
+    use std::io::Read;
+
+    fn certify_asset(path: &str, content: Vec<u8>) {
+        let config = AssetConfig::File {
+            path: path.to_string(),
+            content_type: Some("application/octet-stream".to_string()),
+            headers: vec![],
+            fallback_for: vec![],
+            aliased_by: vec![],
+            encodings: vec![],
+        };
+        ASSET_ROUTER.with_borrow_mut(|asset_router| {
+            certify(
+                asset_router,
+                vec![(path.to_string(), Cow::Owned(content))],
+                vec![config],
+            );
+            certify_custom_responses(asset_router);
+        });
+    }
+
+    // The files certified like bundled ones, under the bundled asset configs
+    fn certify_files(files: AssetContents) {
+        let (assets, precompressed) = split_precompressed(files);
+        let asset_configs = asset_configs();
+        let encoded = encode_assets(&asset_configs, &assets, &precompressed);
+        let configs = specialize_asset_configs(&asset_configs, &assets, &encoded, &BTreeMap::new());
+        ASSET_ROUTER.with_borrow_mut(|asset_router| {
+            certify(asset_router, [assets, encoded].concat(), configs);
+            certify_custom_responses(asset_router);
+        });
+    }
+
+    // The certification of certify_all_assets, with index.html being the given render
+    // instead of one of the canister status
+    fn certify_site(index_html: &'static [u8]) {
+        certify_site_with(vec![("index.html".to_string(), Cow::Borrowed(index_html))]);
+    }
+
+    fn certify_site_with(dynamic_assets: AssetContents) {
+        let (static_assets, precompressed) = split_precompressed(static_assets());
+        let asset_configs = asset_configs();
+        let mut uploaded_configs = uploaded_asset_configs();
+        uploaded_configs.extend(asset_configs.iter().cloned());
+        let static_encoded = encode_assets(&uploaded_configs, &static_assets, &precompressed);
+        let dynamic_encoded = encode_assets(&asset_configs, &dynamic_assets, &[]);
+        let mut static_configs = specialize_asset_configs(
+            &uploaded_configs,
+            &static_assets,
+            &static_encoded,
+            &static_modified_at(&static_assets),
+        );
+        static_configs.extend(redirect_configs());
+        let dynamic_configs = specialize_asset_configs(
+            &asset_configs,
+            &dynamic_assets,
+            &dynamic_encoded,
+            &BTreeMap::new(),
+        );
+        HTTP_TREE.with(|tree| *tree.borrow_mut() = HttpCertificationTree::default());
+        ENCODING_OVERRIDES.take();
+        CUSTOM_RESPONSES.take();
+        ASSET_ROUTER.with_borrow_mut(|asset_router| {
+            *asset_router = AssetRouter::with_tree(HTTP_TREE.with(|tree| tree.clone()));
+            certify(
+                asset_router,
+                [static_assets.clone(), static_encoded].concat(),
+                static_configs,
+            );
+            certify(
+                asset_router,
+                [dynamic_assets.clone(), dynamic_encoded].concat(),
+                dynamic_configs,
+            );
+        });
+        STATIC_PATHS.set(static_assets.into_iter().map(|(path, _)| path).collect());
+        DYNAMIC_ASSETS.set(CertifiedAssets {
+            assets: dynamic_assets,
+            ..Default::default()
+        });
+        ASSET_ROUTER.with_borrow(certify_custom_responses);
+    }
+
+    fn get(url: &str, range: Option<String>) -> HttpRequest<'static> {
+        let headers = range.map(|range| vec![("range".to_string(), range)]);
+        HttpRequest::get(url.to_string())
+            .with_headers(headers.unwrap_or_default())
+            .build()
+    }
+
+    fn get_with(url: &str, headers: &[(&str, &str)]) -> HttpRequest<'static> {
+        HttpRequest::get(url.to_string())
+            .with_headers(
+                headers
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+            )
+            .build()
+    }
+
+    // Serves the request as a query, checking the response verifies
+    fn serve_verified(req: &HttpRequest) -> HttpResponse<'static> {
+        let response = serve_asset(req, Some(vec![1]));
+        verify(req, &response);
+        response
+    }
+
+    // Verifies the response the way a boundary node does: its expression is the router's,
+    // it's served from the most specific path the tree has for the URL, the tree has the
+    // leaf of the request and response hashes under that path, and its certificate header
+    // carries the witness of that leaf against the root hash.
+    fn verify(req: &HttpRequest, response: &HttpResponse) {
+        let url = req.get_path().unwrap();
+        let certificate = get_header(response.headers(), "ic-certificate")
+            .expect("no certificate header")
+            .to_string();
+        let cel = get_header(response.headers(), CERTIFICATE_EXPRESSION_HEADER_NAME)
+            .expect("no expression header")
+            .to_string();
+        let request_headers: Vec<&str> = cel
+            .split("certified_request_headers:[")
+            .nth(1)
+            .and_then(|rest| rest.split(']').next())
+            .unwrap()
+            .split(',')
+            .map(|name| name.trim_matches('"'))
+            .filter(|name| !name.is_empty())
+            .collect();
+        let cel_expr = full_cel_expr(&request_headers);
+        assert_eq!(cel_expr.to_string(), cel);
+
+        let uncertified = HttpResponse::builder()
+            .with_status_code(response.status_code())
+            .with_headers(
+                response
+                    .headers()
+                    .iter()
+                    .filter(|(name, _)| !name.eq_ignore_ascii_case("ic-certificate"))
+                    .cloned()
+                    .collect(),
+            )
+            .with_body(response.body().to_vec())
+            .build();
+        let tree = HTTP_TREE.with(|tree| tree.borrow().clone());
+        let path = std::iter::once(HttpCertificationPath::exact(url.clone()))
+            .chain(
+                wildcard_scopes(&url)
+                    .into_iter()
+                    .map(HttpCertificationPath::wildcard),
+            )
+            .find(|path| {
+                let probe =
+                    HttpCertificationTreeEntry::new(path.clone(), HttpCertification::skip());
+                let witness = tree.witness(&probe, &url).unwrap();
+                matches!(
+                    witness.lookup_subtree(&path.to_expr_path()),
+                    SubtreeLookupResult::Found(_)
+                )
+            })
+            .expect("no certified path for the URL");
+
+        let certification = HttpCertification::full(&cel_expr, req, &uncertified, None).unwrap();
+        let entry = HttpCertificationTreeEntry::new(path.clone(), certification);
+        let witness = tree.witness(&entry, &url).unwrap();
+        assert_eq!(witness.digest(), tree.root_hash());
+        let mut leaf: Vec<Vec<u8>> = path
+            .to_expr_path()
+            .into_iter()
+            .map(String::into_bytes)
+            .collect();
+        leaf.push(Sha256::digest(cel.as_bytes()).to_vec());
+        leaf.push(request_hash(req, &cel_expr.request).unwrap().to_vec());
+        leaf.push(response_hash(&uncertified, &cel_expr.response, None).to_vec());
+        assert_eq!(witness.lookup_path(&leaf), LookupResult::Found(&[]));
+
+        let mut expected = uncertified;
+        add_v2_certificate_header(&[1], &mut expected, &witness, &path.to_expr_path());
+        assert_eq!(
+            get_header(expected.headers(), "ic-certificate"),
+            Some(certificate.as_str())
+        );
+    }
+
+    #[test]
+    fn large_assets_are_served_in_certified_chunks() {
+        let content: Vec<u8> = (0..2 * ASSET_CHUNK_SIZE + 12).map(|i| i as u8).collect();
+        certify_asset("big.bin", content.clone());
+
+        let mut response = serve_asset(&get("/big.bin", None), Some(vec![1]));
+        let mut reassembled = vec![];
+        loop {
+            assert_eq!(response.status_code(), StatusCode::PARTIAL_CONTENT);
+            assert!(get_header(response.headers(), "ic-certificate").is_some());
+            assert!(response.body().len() <= ASSET_CHUNK_SIZE);
+            reassembled.extend_from_slice(response.body());
+            if reassembled.len() == content.len() {
+                break;
+            }
+            let range = format!("bytes={}-", reassembled.len());
+            response = serve_asset(&get("/big.bin", Some(range)), Some(vec![1]));
+        }
+        assert_eq!(reassembled, content);
+    }
+
+    #[test]
+    fn uncertified_ranges_are_upgraded() {
+        certify_asset("small.txt", b"0123456789".to_vec());
+        let req = get("/small.txt", Some("bytes=2-4".to_string()));
+
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.upgrade(), Some(true));
+
+        let response = serve_asset(&req, Some(vec![]));
+        assert_eq!(response.status_code(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.body(), b"234");
+        assert_eq!(
+            get_header(response.headers(), "content-range"),
+            Some("bytes 2-4/10")
+        );
+    }
+
+    #[test]
+    fn parse_range_clamps_to_the_body() {
+        assert_eq!(parse_range("bytes=0-4", 10), Some((0, 4)));
+        assert_eq!(parse_range("bytes=5-", 10), Some((5, 9)));
+        assert_eq!(parse_range("bytes=-3", 10), Some((7, 9)));
+        assert_eq!(parse_range("bytes=-20", 10), Some((0, 9)));
+        assert_eq!(parse_range("bytes=8-100", 10), Some((8, 9)));
+        assert_eq!(parse_range("bytes=0-1, 4-5", 10), Some((0, 1)));
+    }
+
+    #[test]
+    fn parse_range_rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=-0", 10), None);
+        assert_eq!(parse_range("bytes=5-2", 10), None);
+        assert_eq!(parse_range("bytes=10-", 10), None);
+        assert_eq!(parse_range("bytes=0-", 0), None);
+        assert_eq!(parse_range("items=0-1", 10), None);
+        assert_eq!(parse_range("bytes=a-b", 10), None);
+    }
+
+    #[test]
+    fn asset_bytes_are_the_certified_body() {
+        certify_asset("data.bin", b"certified".to_vec());
+        assert_eq!(asset_bytes("/data.bin"), Some(b"certified".to_vec()));
+        assert_eq!(asset_bytes("data.bin"), Some(b"certified".to_vec()));
+        assert_eq!(asset_bytes("/missing.bin"), None);
+    }
+
+    #[test]
+    fn ic_domains_is_served_as_text() {
+        certify_site(b"<html>status</html>");
+        let req = get("/.well-known/ic-domains", None);
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(
+            get_header(response.headers(), "content-type"),
+            Some("text/plain")
+        );
+        verify(&req, &response);
+    }
+
+    #[test]
+    fn cleared_uploads_are_no_longer_served() {
+        upload::insert_uploaded_asset("notes.txt".to_string(), b"notes".to_vec(), "text/plain", 0);
+        certify_site(b"<html>status</html>");
+        let req = get("/notes.txt", None);
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.body(), b"notes");
+
+        assert_eq!(upload::clear_uploads(), 1);
+        certify_site(b"<html>status</html>");
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+        verify(&req, &response);
+    }
+
+    #[test]
+    fn the_tree_dump_has_the_leaf_of_each_served_response() {
+        certify_site(b"<html>status</html>");
+        let dump = dump_certification_tree();
+
+        for url in ["/index.css", "/missing"] {
+            let req = get(url, None);
+            let response = serve_asset(&req, Some(vec![1]));
+            verify(&req, &response);
+            let expr_path = match response.status_code() {
+                StatusCode::OK => "http_expr/index.css/<$>",
+                _ => "http_expr//<*>",
+            };
+            let uncertified = HttpResponse::builder()
+                .with_status_code(response.status_code())
+                .with_headers(
+                    response
+                        .headers()
+                        .iter()
+                        .filter(|(name, _)| name != "ic-certificate")
+                        .cloned()
+                        .collect(),
+                )
+                .with_body(response.body().to_vec())
+                .build();
+            let cel = get_header(response.headers(), CERTIFICATE_EXPRESSION_HEADER_NAME).unwrap();
+            let leaf = (
+                expr_path.to_string(),
+                hex::encode(Sha256::digest(cel.as_bytes())),
+                hex::encode(response_hash(
+                    &uncertified,
+                    &full_cel_expr(&[]).response,
+                    None,
+                )),
+            );
+            assert!(dump.contains(&leaf), "{}", url);
+        }
+    }
+
+    #[test]
+    fn encoded_variants_carry_their_configured_cache_control() {
+        config::update_config(|config| {
+            config
+                .encoding_cache_control
+                .insert("gzip".to_string(), "public, max-age=60".to_string());
+        });
+        let config = AssetConfig::File {
+            path: "app.js".to_string(),
+            content_type: Some("text/javascript".to_string()),
+            headers: vec![(
+                "cache-control".to_string(),
+                "public, max-age=3600".to_string(),
+            )],
+            fallback_for: vec![],
+            aliased_by: vec![],
+            encodings: vec![(AssetEncoding::Gzip, ".gz".to_string())],
+        };
+        ASSET_ROUTER.with_borrow_mut(|asset_router| {
+            certify(
+                asset_router,
+                vec![
+                    ("app.js".to_string(), Cow::Owned(b"identity".to_vec())),
+                    ("app.js.gz".to_string(), Cow::Owned(b"gzipped".to_vec())),
+                ],
+                vec![config],
+            )
+        });
+
+        let req = HttpRequest::get("/app.js".to_string())
+            .with_headers(vec![("accept-encoding".to_string(), "gzip".to_string())])
+            .build();
+        let gzip = serve_asset(&req, Some(vec![1]));
+        assert_eq!(gzip.body(), b"gzipped");
+        assert_eq!(get_header(gzip.headers(), "content-encoding"), Some("gzip"));
+        assert_eq!(
+            get_header(gzip.headers(), "cache-control"),
+            Some("public, max-age=60")
+        );
+        verify(&req, &gzip);
+
+        let req = get("/app.js", None);
+        let identity = serve_asset(&req, Some(vec![1]));
+        assert_eq!(identity.body(), b"identity");
+        assert_eq!(
+            get_header(identity.headers(), "cache-control"),
+            Some("public, max-age=3600")
+        );
+        verify(&req, &identity);
+    }
+
+    #[test]
+    fn failures_get_the_certified_500_page() {
+        // Nothing answers /missing.bin, not even a fallback
+        certify_asset("data.bin", b"certified".to_vec());
+        let req = get("/missing.bin", None);
+
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            response.body(),
+            ASSETS_DIR.get_file("500.html").unwrap().contents()
+        );
+        verify(&req, &response);
+    }
+
+    #[test]
+    fn missing_assets_get_a_500_instead_of_a_trap() {
+        certify_asset("data.bin", b"certified".to_vec());
+        let req = get("/missing.bin", None);
+
+        // Update calls get the certified page too, having no certificate to attach
+        let response = serve_asset(&req, Some(vec![]));
+        assert_eq!(response.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let response = serve_asset(&req, None);
+        assert_eq!(response.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(get_header(response.headers(), "ic-certificate").is_none());
+    }
+
+    #[test]
+    fn unknown_paths_get_the_certified_404_page() {
+        certify_site(b"<html>status</html>");
+        let not_found = ASSETS_DIR.get_file("404.html").unwrap().contents();
+
+        for url in ["/does-not-exist", "/a/b/does-not-exist", "/missing.css"] {
+            let response = serve_asset(&get(url, None), Some(vec![1]));
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND, "{}", url);
+            assert_eq!(response.body(), not_found, "{}", url);
+            assert!(get_header(response.headers(), "ic-certificate").is_some());
+            assert!(get_header(response.headers(), "ic-certificateexpression").is_some());
+        }
+    }
+
+    #[test]
+    fn spa_routes_get_the_app_and_missing_files_a_certified_404() {
+        config::update_config(|config| config.spa_mode = true);
+        certify_site(b"<html>app</html>");
+
+        let req = get("/dashboard/42", None);
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.body(), b"<html>app</html>");
+        verify(&req, &response);
+
+        let req = get("/missing.js", None);
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.body(),
+            ASSETS_DIR.get_file("404.html").unwrap().contents()
+        );
+        verify(&req, &response);
+    }
+
+    #[test]
+    fn the_status_page_behind_auth_gets_a_certified_401() {
+        config::update_config(|config| {
+            config.metrics_auth = Some((
+                "admin".to_string(),
+                auth::credentials_hash("admin", "secret"),
+            ));
+        });
+        certify_site(b"<html>status</html>");
+
+        for url in ["/", "/index.html"] {
+            let req = get(url, None);
+            let response = serve_asset(&req, Some(vec![1]));
+            assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED, "{}", url);
+            assert!(get_header(response.headers(), "www-authenticate").is_some());
+            verify(&req, &response);
+        }
+
+        let req = HttpRequest::get("/".to_string())
+            .with_headers(vec![(
+                "authorization".to_string(),
+                format!("Basic {}", STANDARD.encode("admin:secret")),
+            )])
+            .build();
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.body(), b"<html>status</html>");
+        verify(&req, &response);
+
+        // The bundled app stays public
+        let req = get("/index.js", None);
+        assert_eq!(
+            serve_asset(&req, Some(vec![1])).status_code(),
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn a_single_allowed_origin_is_certified() {
+        config::update_config(|config| {
+            config.cors_allowed_origins = vec!["https://app.example".to_string()];
+        });
+        certify_site(b"<html>status</html>");
+
+        for url in ["/index.html", "/index.css", "/missing"] {
+            let req = HttpRequest::get(url.to_string())
+                .with_headers(vec![(
+                    "origin".to_string(),
+                    "https://app.example".to_string(),
+                )])
+                .build();
+            let response = serve_asset(&req, Some(vec![1]));
+            assert_eq!(
+                get_header(response.headers(), "access-control-allow-origin"),
+                Some("https://app.example"),
+                "{}",
+                url
+            );
+            verify(&req, &response);
+        }
+    }
+
+    #[test]
+    fn preferred_languages_get_a_certified_redirect() {
+        certify_site_with(vec![
+            ("index.html".to_string(), Cow::Borrowed(b"<html>en</html>")),
+            (
+                "index.fr.html".to_string(),
+                Cow::Borrowed(b"<html>fr</html>"),
+            ),
+        ]);
+
+        let req = HttpRequest::get("/".to_string())
+            .with_headers(vec![(
+                "accept-language".to_string(),
+                "fr-CA, en;q=0.8".to_string(),
+            )])
+            .build();
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(get_header(response.headers(), "location"), Some("/fr"));
+        assert_eq!(
+            get_header(response.headers(), "vary"),
+            Some("Accept-Language")
+        );
+        verify(&req, &response);
+
+        let req = get("/", None);
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.body(), b"<html>en</html>");
+        verify(&req, &response);
+    }
+
+    #[test]
+    fn alias_redirects_are_certified() {
+        config::update_config(|config| {
+            let target = "/index.html".to_string();
+            config
+                .alias_redirects
+                .insert("/old".to_string(), (target.clone(), 301));
+            config
+                .alias_redirects
+                .insert("/later".to_string(), (target, 302));
+        });
+        certify_site(b"<html>status</html>");
+
+        for (url, status) in [
+            ("/old", StatusCode::MOVED_PERMANENTLY),
+            ("/later", StatusCode::FOUND),
+        ] {
+            let req = get(url, None);
+            let response = serve_asset(&req, Some(vec![1]));
+            assert_eq!(response.status_code(), status, "{}", url);
+            assert_eq!(
+                get_header(response.headers(), "location"),
+                Some("/index.html")
+            );
+            verify(&req, &response);
+        }
+    }
+
+    #[test]
+    fn a_matching_etag_gets_a_certified_304() {
+        certify_site(b"<html>status</html>");
+        let etag = etag(b"<html>status</html>");
+        let conditional = |value: &str| {
+            HttpRequest::get("/".to_string())
+                .with_headers(vec![("if-none-match".to_string(), value.to_string())])
+                .build()
+        };
+
+        let req = conditional(&etag);
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::NOT_MODIFIED);
+        assert!(response.body().is_empty());
+        assert_eq!(get_header(response.headers(), "etag"), Some(etag.as_str()));
+        assert!(get_header(response.headers(), "content-length").is_none());
+        verify(&req, &response);
+
+        let req = conditional("\"stale\"");
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.body(), b"<html>status</html>");
+        verify(&req, &response);
+    }
+
+    #[test]
+    fn an_unchanged_last_modified_gets_a_certified_304() {
+        certify_site(b"<html>status</html>");
+        let req = get("/index.css", None);
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        let last_modified = get_header(response.headers(), "last-modified")
+            .unwrap()
+            .to_string();
+        let conditional = |headers: Vec<(&str, &str)>| {
+            let headers = headers
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            HttpRequest::get("/index.css".to_string())
+                .with_headers(headers)
+                .build()
+        };
+
+        let req = conditional(vec![("if-modified-since", &last_modified)]);
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::NOT_MODIFIED);
+        assert!(response.body().is_empty());
+        verify(&req, &response);
+
+        let req = conditional(vec![("if-modified-since", "Mon, 01 Jan 2001 00:00:00 GMT")]);
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert!(!response.body().is_empty());
+        verify(&req, &response);
+
+        // A stale ETag wins over the date
+        let req = conditional(vec![
+            ("if-none-match", "\"stale\""),
+            ("if-modified-since", &last_modified),
+        ]);
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        verify(&req, &response);
+    }
+
+    #[test]
+    fn head_requests_get_the_certified_headers_without_a_body() {
+        certify_site(b"<html>status</html>");
+        let head = |url: &str| {
+            HttpRequest::builder()
+                .with_method(Method::HEAD)
+                .with_url(url.to_string())
+                .build()
+        };
+
+        let req = head("/index.html");
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert!(response.body().is_empty());
+        assert_eq!(
+            get_header(response.headers(), "content-type"),
+            Some("text/html")
+        );
+        assert_eq!(
+            get_header(response.headers(), "content-length"),
+            Some(b"<html>status</html>".len().to_string().as_str())
+        );
+        verify(&req, &response);
+
+        // Fallbacks have theirs as well
+        let req = head("/does-not-exist");
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+        assert!(response.body().is_empty());
+        verify(&req, &response);
+    }
+
+    #[test]
+    fn the_rendered_index_is_served_brotli_compressed() {
+        let rendered: &'static [u8] = "<p>rendered status</p>".repeat(100).leak().as_bytes();
+        certify_site(rendered);
+        let req = HttpRequest::get("/".to_string())
+            .with_headers(vec![("accept-encoding".to_string(), "br".to_string())])
+            .build();
+
+        let response = serve_asset(&req, Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(
+            get_header(response.headers(), "content-encoding"),
+            Some("br")
+        );
+        assert!(get_header(response.headers(), "ic-certificate").is_some());
+        let mut decompressed = vec![];
+        brotli::BrotliDecompress(&mut &response.body()[..], &mut decompressed).unwrap();
+        assert_eq!(decompressed, rendered);
+    }
+
+    #[test]
+    fn precompressed_files_are_served_as_their_encoding() {
+        let script = "console.log('precompressed');\n".repeat(50).into_bytes();
+        let precompressed = compress_brotli(&script, 0).unwrap();
+        let quality = config::with_config(|config| config.brotli_quality);
+        assert_ne!(
+            compress_brotli(&script, quality),
+            Some(precompressed.clone())
+        );
+        certify_files(vec![
+            ("x.js".to_string(), Cow::Owned(script.clone())),
+            ("x.js.br".to_string(), Cow::Owned(precompressed.clone())),
+        ]);
+
+        let response = serve_verified(&get_with("/x.js", &[("accept-encoding", "br")]));
+        assert_eq!(
+            get_header(response.headers(), "content-encoding"),
+            Some("br")
+        );
+        assert_eq!(response.body(), precompressed);
+        let response = serve_verified(&get("/x.js", None));
+        assert_eq!(response.body(), script);
+    }
+
+    #[test]
+    fn excluded_paths_drop_the_cross_origin_headers() {
+        config::update_config(|config| config.header_exclusions = vec!["*.css".to_string()]);
+        certify_site(b"<html>status</html>");
+
+        let excluded = serve_verified(&get("/index.css", None));
+        assert_eq!(excluded.status_code(), StatusCode::OK);
+        assert_eq!(
+            get_header(excluded.headers(), "cross-origin-embedder-policy"),
+            None
+        );
+        assert_eq!(
+            get_header(excluded.headers(), "cross-origin-opener-policy"),
+            None
+        );
+        let normal = serve_verified(&get("/index.js", None));
+        assert_eq!(
+            get_header(normal.headers(), "cross-origin-embedder-policy"),
+            Some("require-corp")
+        );
+    }
+
+    #[test]
+    fn gzip_only_clients_get_the_gzip_variant() {
+        let rendered: &'static [u8] = "<p>rendered status</p>".repeat(100).leak().as_bytes();
+        certify_site(rendered);
+        let response = serve_verified(&get_with("/", &[("accept-encoding", "gzip")]));
+        assert_eq!(
+            get_header(response.headers(), "content-encoding"),
+            Some("gzip")
+        );
+        let mut decompressed = vec![];
+        flate2::read::GzDecoder::new(response.body())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, rendered);
+    }
+
+    #[test]
+    fn bundled_file_types_get_their_content_type() {
+        certify_files(vec![
+            ("logo.svg".to_string(), Cow::Borrowed(b"<svg/>")),
+            ("data.json".to_string(), Cow::Borrowed(b"{}")),
+            ("app.wasm".to_string(), Cow::Borrowed(b"\0asm")),
+            ("font.woff2".to_string(), Cow::Borrowed(b"wOF2")),
+            ("icon.ico".to_string(), Cow::Borrowed(b"\0\0\x01\0")),
+        ]);
+
+        let immutable = immutable_cache_control();
+        for (url, content_type, cache_control) in [
+            ("/logo.svg", "image/svg+xml", immutable.as_str()),
+            ("/data.json", "application/json", NO_CACHE_CONTROL),
+            ("/app.wasm", "application/wasm", immutable.as_str()),
+            ("/font.woff2", "font/woff2", immutable.as_str()),
+            ("/icon.ico", "image/x-icon", immutable.as_str()),
+        ] {
+            let response = serve_verified(&get(url, None));
+            assert_eq!(response.status_code(), StatusCode::OK, "{}", url);
+            let headers = response.headers();
+            assert_eq!(get_header(headers, "content-type"), Some(content_type));
+            assert_eq!(get_header(headers, "cache-control"), Some(cache_control));
+        }
+    }
+
+    #[test]
+    fn robots_and_sitemap_get_their_content_type() {
+        // robots.txt is synthesized when none is bundled
+        certify_site(b"<html>status</html>");
+        let robots = serve_verified(&get("/robots.txt", None));
+        assert_eq!(robots.body(), DEFAULT_ROBOTS_TXT);
+        assert_eq!(
+            get_header(robots.headers(), "content-type"),
+            Some("text/plain")
+        );
+
+        certify_files(vec![(
+            "sitemap.xml".to_string(),
+            Cow::Borrowed(b"<urlset/>"),
+        )]);
+        let sitemap = serve_verified(&get("/sitemap.xml", None));
+        assert_eq!(sitemap.status_code(), StatusCode::OK);
+        assert_eq!(
+            get_header(sitemap.headers(), "content-type"),
+            Some("application/xml")
+        );
+    }
+
+    #[test]
+    fn security_txt_is_served_with_the_configured_contact() {
+        config::update_config(|config| {
+            config.security_contact = Some("mailto:security@example.com".to_string());
+        });
+        certify_site(b"<html>status</html>");
+        let response = serve_verified(&get("/.well-known/security.txt", None));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(
+            get_header(response.headers(), "content-type"),
+            Some("text/plain")
+        );
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(body.starts_with("Contact: mailto:security@example.com\nExpires: "));
+    }
+
+    #[test]
+    fn a_favicon_is_synthesized_when_none_is_bundled() {
+        certify_site(b"<html>status</html>");
+        let response = serve_verified(&get("/favicon.ico", None));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.body(), DEFAULT_FAVICON);
+        assert_eq!(
+            get_header(response.headers(), "content-type"),
+            Some("image/x-icon")
+        );
+        assert_eq!(
+            get_header(response.headers(), "cache-control"),
+            Some(immutable_cache_control().as_str())
+        );
+    }
+
+    #[test]
+    fn content_length_is_the_length_of_the_served_encoding() {
+        let rendered: &'static [u8] = "<p>rendered status</p>".repeat(100).leak().as_bytes();
+        certify_site(rendered);
+        for encoding in ["identity", "gzip", "br"] {
+            let response = serve_verified(&get_with("/", &[("accept-encoding", encoding)]));
+            let content_length = response.body().len().to_string();
+            assert_eq!(
+                get_header(response.headers(), "content-length"),
+                Some(content_length.as_str()),
+                "{}",
+                encoding
+            );
+        }
+    }
+
+    #[test]
+    fn only_assets_with_encodings_vary_on_accept_encoding() {
+        certify_site(b"<html>status</html>");
+        let css = serve_verified(&get_with("/index.css", &[("accept-encoding", "br")]));
+        assert_eq!(get_header(css.headers(), "content-encoding"), Some("br"));
+        assert_eq!(get_header(css.headers(), "vary"), Some("accept-encoding"));
+        let css = serve_verified(&get("/index.css", None));
+        assert_eq!(get_header(css.headers(), "vary"), Some("accept-encoding"));
+
+        // An empty script doesn't compress, so it's only certified as is
+        let js = serve_verified(&get_with("/index.js", &[("accept-encoding", "br")]));
+        assert_eq!(get_header(js.headers(), "content-encoding"), None);
+        assert_eq!(get_header(js.headers(), "vary"), None);
+    }
+
+    #[test]
+    fn download_patterns_get_a_content_disposition() {
+        config::update_config(|config| config.download_patterns = vec!["exports/*".to_string()]);
+        certify_files(vec![
+            ("exports/report.json".to_string(), Cow::Borrowed(b"{}")),
+            ("data.json".to_string(), Cow::Borrowed(b"{}")),
+        ]);
+
+        let download = serve_verified(&get("/exports/report.json", None));
+        assert_eq!(
+            get_header(download.headers(), "content-disposition"),
+            Some("attachment; filename=\"report.json\"")
+        );
+        let inline = serve_verified(&get("/data.json", None));
+        assert_eq!(get_header(inline.headers(), "content-disposition"), None);
+    }
+
+    #[test]
+    fn an_alias_serves_its_target() {
+        config::update_config(|config| {
+            config
+                .aliases
+                .insert("/home".to_string(), "/index.html".to_string());
+        });
+        certify_site(b"<html>status</html>");
+        let response = serve_verified(&get("/home", None));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.body(), b"<html>status</html>");
+    }
+
+    #[test]
+    fn assets_are_served_under_the_base_path() {
+        config::update_config(|config| config.base_path = "/status".to_string());
+        certify_site(b"<html>status</html>");
+
+        for url in ["/status/index.html", "/status"] {
+            let response = serve_verified(&get(url, None));
+            assert_eq!(response.status_code(), StatusCode::OK, "{}", url);
+            assert_eq!(response.body(), b"<html>status</html>");
+        }
+        let outside = serve_verified(&get("/index.html", None));
+        assert_eq!(outside.status_code(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn each_subtree_gets_its_own_404_page() {
+        config::update_config(|config| {
+            config
+                .fallbacks
+                .insert("/admin".to_string(), ("admin-404.html".to_string(), 404));
+        });
+        certify_site_with(vec![
+            (
+                "index.html".to_string(),
+                Cow::Borrowed(b"<html>status</html>"),
+            ),
+            (
+                "admin-404.html".to_string(),
+                Cow::Borrowed(b"<html>no such admin page</html>"),
+            ),
+        ]);
+
+        let admin = serve_verified(&get("/admin/missing", None));
+        assert_eq!(admin.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(admin.body(), b"<html>no such admin page</html>");
+        let app = serve_verified(&get("/app/missing", None));
+        assert_eq!(app.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            app.body(),
+            ASSETS_DIR.get_file("404.html").unwrap().contents()
+        );
+    }
+
+    #[test]
+    fn no_certificate_gets_a_500_instead_of_a_trap() {
+        certify_site(b"<html>status</html>");
+        let response = serve_asset(&get("/index.html", None), None);
+        assert_eq!(response.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(get_header(response.headers(), "ic-certificate"), None);
+        assert_eq!(
+            response.body(),
+            ASSETS_DIR.get_file("500.html").unwrap().contents()
+        );
+    }
+
+    #[test]
+    fn healthz_answers_ok() {
+        certify_site(b"<html>status</html>");
+        let response = serve_verified(&get("/healthz", None));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.body(), b"ok");
+        assert_eq!(
+            get_header(response.headers(), "content-type"),
+            Some("text/plain")
+        );
+    }
+
+    #[test]
+    fn redirects_get_a_certified_301() {
+        config::update_config(|config| {
+            config
+                .redirects
+                .insert("/moved".to_string(), ("/index.html".to_string(), true));
+        });
+        certify_site(b"<html>status</html>");
+        let response = serve_verified(&get("/moved", None));
+        assert_eq!(response.status_code(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            get_header(response.headers(), "location"),
+            Some("/index.html")
+        );
+    }
+
+    #[test]
+    fn maintenance_mode_serves_a_certified_503() {
+        certify_maintenance(120);
+        for url in ["/", "/index.html", "/a/b/missing"] {
+            let response = serve_verified(&get(url, None));
+            assert_eq!(
+                response.status_code(),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "{}",
+                url
+            );
+            assert_eq!(get_header(response.headers(), "retry-after"), Some("120"));
+            assert_eq!(
+                response.body(),
+                ASSETS_DIR.get_file("maintenance.html").unwrap().contents()
+            );
+        }
+        let probe = serve_verified(&get("/healthz", None));
+        assert_eq!(probe.status_code(), StatusCode::OK);
+    }
+
+    #[test]
+    fn the_debug_header_is_certified_when_enabled() {
+        for (enabled, version) in [(false, None), (true, Some(CERTIFICATION_VERSION))] {
+            config::update_config(|config| config.debug_headers = enabled);
+            certify_site(b"<html>status</html>");
+            for url in ["/index.html", "/missing"] {
+                let response = serve_verified(&get(url, None));
+                assert_eq!(
+                    get_header(response.headers(), "x-ic-certification-version"),
+                    version,
+                    "{}",
+                    url
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn a_failed_first_render_certifies_the_placeholder() {
+        let err = "canister_status failed".to_string();
+        assert_eq!(certify_all_with(Err(err.clone())), Err(err));
+        let response = serve_verified(&get("/", None));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.body(), PLACEHOLDER_INDEX_HTML);
+        let css = serve_verified(&get("/index.css", None));
+        assert_eq!(css.status_code(), StatusCode::OK);
+    }
+
+    #[test]
+    fn a_failed_refresh_keeps_serving_the_last_render_as_stale() {
+        let rendered = vec![(
+            "index.html".to_string(),
+            Cow::Borrowed(&b"<html>last good status</html>"[..]),
+        )];
+        certify_all_with(Ok(rendered)).unwrap();
+        let fresh = serve_verified(&get("/", None));
+        assert_eq!(get_header(fresh.headers(), "x-content-stale"), None);
+
+        let err = "canister_status failed".to_string();
+        assert_eq!(certify_dynamic_with(Err(err.clone())), Err(err.clone()));
+        let stale = serve_verified(&get("/", None));
+        assert_eq!(stale.body(), b"<html>last good status</html>");
+        assert_eq!(get_header(stale.headers(), "x-content-stale"), Some("true"));
+
+        // A full run keeps it as well
+        assert_eq!(certify_all_with(Err(err.clone())), Err(err));
+        let stale = serve_verified(&get("/", None));
+        assert_eq!(stale.body(), b"<html>last good status</html>");
+        assert_eq!(get_header(stale.headers(), "x-content-stale"), Some("true"));
+    }
+
+    #[test]
+    fn every_template_renders_into_its_own_certified_page() {
+        let history = File::new("history.hbs", b"{{> header}}<p>{{module_hash}}</p>");
+        let controllers = File::new("ops/controllers.hbs", b"{{> header}}<p>{{canister_id}}</p>");
+        let header = File::new("partials/header.hbs", b"<h1>{{status}}</h1>");
+        let templates = [
+            ("history".to_string(), &history),
+            ("ops/controllers".to_string(), &controllers),
+        ];
+        let partials = [("header".to_string(), &header)];
+        let status = DefiniteCanisterStatus::fixture();
+
+        let rendered = serve_canister_info(&templates, &partials, &status);
+        assert_eq!(
+            rendered,
+            vec![
+                (
+                    "history".to_string(),
+                    format!("<h1>running</h1><p>{}</p>", "ab".repeat(32))
+                ),
+                (
+                    "ops/controllers".to_string(),
+                    "<h1>running</h1><p>ryjl3-tyaaa-aaaaa-aaaba-cai</p>".to_string()
+                ),
+            ]
+        );
+
+        certify_site_with(
+            rendered
+                .into_iter()
+                .map(|(name, html)| (format!("{}.html", name), Cow::Owned(html.into_bytes())))
+                .collect(),
+        );
+        let response = serve_verified(&get("/history.html", None));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert!(response.body().ends_with(b"</p>"));
+        let response = serve_verified(&get("/ops/controllers.html", None));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert!(response.body().starts_with(b"<h1>running</h1>"));
+    }
+
+    #[test]
+    fn an_empty_assets_directory_serves_a_placeholder_at_the_root() {
+        let mut static_assets = vec![];
+        add_missing_assets_placeholder(&Dir::new("", &[]), &mut static_assets, &Vec::new());
+        assert_eq!(
+            static_assets,
+            vec![("index.html".to_string(), Cow::Borrowed(MISSING_ASSETS_HTML))]
+        );
+
+        certify_files(static_assets);
+        let response = serve_verified(&get("/", None));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.body(), MISSING_ASSETS_HTML);
+
+        // The bundled directory, or a render of index.html, needs no placeholder
+        let mut static_assets = vec![];
+        add_missing_assets_placeholder(&ASSETS_DIR, &mut static_assets, &Vec::new());
+        assert!(static_assets.is_empty());
+        let dynamic_assets = vec![("index.html".to_string(), Cow::Borrowed(&b"<p></p>"[..]))];
+        add_missing_assets_placeholder(&Dir::new("", &[]), &mut static_assets, &dynamic_assets);
+        assert!(static_assets.is_empty());
+    }
+
+    #[test]
+    fn prefer_gzip_picks_gzip_over_brotli() {
+        let rendered: &'static [u8] = "<p>rendered status</p>".repeat(100).leak().as_bytes();
+        for (prefer_gzip, encoding) in [(false, "br"), (true, "gzip")] {
+            config::update_config(|config| config.prefer_gzip = prefer_gzip);
+            certify_site(rendered);
+            let req = get_with("/", &[("accept-encoding", "br, gzip")]);
+            let response = serve_verified(&req);
+            assert_eq!(
+                get_header(response.headers(), "content-encoding"),
+                Some(encoding)
+            );
+        }
+    }
+
+    #[test]
+    fn normalized_url_collapses_slashes_and_drops_a_trailing_one() {
+        assert_eq!(normalized_url("//foo.js"), Some("/foo.js".to_string()));
+        assert_eq!(normalized_url("/foo.js/"), Some("/foo.js".to_string()));
+        assert_eq!(normalized_url("/a//b/?x=1"), Some("/a/b?x=1".to_string()));
+        assert_eq!(normalized_url("/"), None);
+        assert_eq!(normalized_url("/foo.js"), None);
+        assert_eq!(normalized_url("/foo.js?next=//bar/"), None);
+    }
+
+    #[test]
+    fn url_variants_resolve_to_the_certified_asset() {
+        certify_site(b"<html>status</html>");
+
+        // The redirect is answered by the update call the query is upgraded to
+        for url in ["//index.css", "/index.css/"] {
+            let response = serve_asset(&get(url, None), Some(vec![1]));
+            assert_eq!(response.upgrade(), Some(true));
+            let response = serve_asset(&get(url, None), Some(vec![]));
+            assert_eq!(response.status_code(), StatusCode::PERMANENT_REDIRECT);
+            assert_eq!(
+                get_header(response.headers(), "location"),
+                Some("/index.css")
+            );
+        }
+        let response = serve_asset(&get("/index.css", None), Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(
+            response.body(),
+            ASSETS_DIR.get_file("index.css").unwrap().contents()
+        );
+        let response = serve_asset(&get("/", None), Some(vec![1]));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.body(), b"<html>status</html>");
+    }
+
+    #[test]
+    fn root_hash_is_independent_of_the_asset_order() {
+        let fixture: Vec<(String, Cow<'static, [u8]>)> =
+            ["a.js", "a.js.gz", "a.js.br", "b.css", "c/d.txt", "e.html"]
+                .into_iter()
+                .map(|path| (path.to_string(), Cow::Owned(path.as_bytes().to_vec())))
+                .collect();
+        let root_hash_of = |assets: Vec<(String, Cow<'static, [u8]>)>| {
+            HTTP_TREE.with(|tree| *tree.borrow_mut() = HttpCertificationTree::default());
+            ASSET_ROUTER.with_borrow_mut(|asset_router| {
+                *asset_router = AssetRouter::with_tree(HTTP_TREE.with(|tree| tree.clone()));
+                certify(asset_router, assets, asset_configs());
+            });
+            root_hash()
+        };
+
+        let in_order = root_hash_of(fixture.clone());
+        let reversed = root_hash_of(fixture.into_iter().rev().collect());
+        assert_eq!(in_order, reversed);
+    }
+
+    #[test]
+    fn root_hash_is_identical_across_runs() {
+        certify_site(b"<html>status</html>");
+        let first = root_hash();
+        certify_site(b"<html>status</html>");
+        assert_eq!(root_hash(), first);
+    }
 }
@@ -0,0 +1,56 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ic_http_certification::{HttpRequest, HttpResponse, StatusCode};
+use sha2::{Digest, Sha256};
+
+use crate::asset::get_header;
+use crate::config;
+
+const REALM: &str = "Canister Status";
+
+// The username salts the hash, so equal passwords don't hash the same
+pub(crate) fn credentials_hash(username: &str, password: &str) -> [u8; 32] {
+    Sha256::digest(format!("{}:{}", username, password)).into()
+}
+
+// Always while no credentials are set
+pub(crate) fn is_authorized(req: &HttpRequest) -> bool {
+    let Some((username, hash)) = config::with_config(|config| config.metrics_auth.clone()) else {
+        return true;
+    };
+    get_header(req.headers(), "authorization")
+        .and_then(|value| value.trim().strip_prefix("Basic "))
+        .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .and_then(|decoded| {
+            let (user, password) = decoded.split_once(':')?;
+            Some(user == username && credentials_hash(user, password) == hash)
+        })
+        .unwrap_or(false)
+}
+
+// Guards the queries exposing what the status page renders, only controllers may call
+// them while the page requires credentials
+pub(crate) fn is_status_reader() -> Result<(), String> {
+    match config::with_config(|config| config.metrics_auth.is_some()) {
+        true => config::is_controller(),
+        false => Ok(()),
+    }
+}
+
+// The challenge is the same for every path and carries no content, so it's certified as
+// is for each path it answers
+pub(crate) fn unauthorized() -> HttpResponse<'static> {
+    HttpResponse::builder()
+        .with_status_code(StatusCode::UNAUTHORIZED)
+        .with_headers(vec![
+            (
+                "www-authenticate".to_string(),
+                format!("Basic realm=\"{}\", charset=\"UTF-8\"", REALM),
+            ),
+            ("content-type".to_string(), "text/plain".to_string()),
+            ("cache-control".to_string(), "no-store".to_string()),
+        ])
+        .with_body(b"Unauthorized".to_vec())
+        .build()
+}
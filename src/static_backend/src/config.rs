@@ -0,0 +1,945 @@
+use candid::CandidType;
+use globset::Glob;
+use ic_asset_certification::AssetEncoding;
+use ic_stable_structures::StableCell;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::memory::{self, Memory};
+
+const DEFAULT_UPDATE_INTERVAL_SECS: u64 = 120;
+const DEFAULT_CONTENT_SECURITY_POLICY: &str = "default-src 'self'; img-src 'self' data:; form-action 'self'; object-src 'none'; frame-ancestors 'none'; upgrade-insecure-requests; block-all-mixed-content";
+const DEFAULT_PERMISSIONS_POLICY: &str = "accelerometer=(),ambient-light-sensor=(),autoplay=(),battery=(),camera=(),display-capture=(),document-domain=(),encrypted-media=(),fullscreen=(),gamepad=(),geolocation=(),gyroscope=(),layout-animations=(self),legacy-image-formats=(self),magnetometer=(),microphone=(),midi=(),oversized-images=(self),payment=(),picture-in-picture=(),publickey-credentials-get=(),speaker-selection=(),sync-xhr=(self),unoptimized-images=(self),unsized-media=(self),usb=(),screen-wake-lock=(),web-share=(),xr-spatial-tracking=()";
+const DEFAULT_IMMUTABLE_MAX_AGE_SECS: u64 = 31_536_000;
+const DEFAULT_HTML_CACHE_CONTROL: &str = "public, no-cache, no-store";
+const MAX_HEADER_VALUE_LEN: usize = 4096;
+const DEFAULT_LOW_CYCLES_THRESHOLD: u64 = 5_000_000_000_000;
+// Preload lists require at least a year, see https://hstspreload.org
+const HSTS_PRELOAD_MIN_MAX_AGE_SECS: u64 = 31_536_000;
+const DEFAULT_GZIP_LEVEL: u8 = 6;
+const MAX_GZIP_LEVEL: u8 = 9;
+// Quality 11 takes several times the instructions of 9 for a few percent smaller output
+const DEFAULT_BROTLI_QUALITY: u8 = 9;
+const MAX_BROTLI_QUALITY: u8 = 11;
+// canister_info returns at most 20 changes
+pub(crate) const MAX_HISTORY_LENGTH: u64 = 20;
+
+thread_local! {
+    static CONFIG: RefCell<Config> = RefCell::new(Config::default());
+}
+
+// Saved as JSON, fields missing from a config saved by an earlier release get their
+// default and fields it no longer has are ignored
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub(crate) struct Config {
+    pub update_interval_secs: u64,
+    // Glob pattern to extra headers, applied to every matching asset
+    pub asset_headers: BTreeMap<String, Vec<(String, String)>>,
+    pub content_security_policy: String,
+    pub immutable_max_age_secs: u64,
+    pub history_length: u64,
+    pub encodings: Vec<String>,
+    pub number_separator: String,
+    pub indian_grouping: bool,
+    // Fixed offset from UTC timestamps are displayed in, no daylight saving is applied
+    pub display_offset_secs: i32,
+    pub cors_allowed_origins: Vec<String>,
+    // From path to (target, permanent)
+    pub redirects: BTreeMap<String, (String, bool)>,
+    pub spa_mode: bool,
+    pub gzip_level: u8,
+    pub brotli_quality: u8,
+    // Set while in maintenance mode
+    pub maintenance_retry_after_secs: Option<u32>,
+    pub log_verbose: bool,
+    pub hsts_max_age_secs: u64,
+    pub hsts_include_subdomains: bool,
+    pub hsts_preload: bool,
+    pub count_requests: bool,
+    pub permissions_policy: String,
+    pub low_cycles_threshold: u64,
+    // For index.html and 404.html
+    pub html_cache_control: String,
+    // Username and the hash of username:password gating the status page, see auth
+    pub metrics_auth: Option<(String, [u8; 32])>,
+    // Glob patterns of assets served as attachments
+    pub download_patterns: Vec<String>,
+    // Glob patterns of assets served without COEP and COOP
+    pub header_exclusions: Vec<String>,
+    // Origins allowed to embed the site, none denies all embedding
+    pub frame_ancestors: Vec<String>,
+    pub debug_headers: bool,
+    // From alias to the path of the asset it serves
+    pub aliases: BTreeMap<String, String>,
+    // From alias to (target, redirect status code)
+    pub alias_redirects: BTreeMap<String, (String, u16)>,
+    // Prefix every asset is served under, e.g. /status, empty to serve from the root
+    pub base_path: String,
+    // Largest body accepted on anything but GET, 0 accepts any size
+    pub max_body_bytes: u64,
+    // From scope to (page path, status code), for subtrees with their own error page
+    pub fallbacks: BTreeMap<String, (String, u16)>,
+    pub prefer_gzip: bool,
+    // Contact of the synthesized security.txt, none is served without one
+    pub security_contact: Option<String>,
+    // From content-encoding to the cache-control its variants are served with instead
+    // of the one of their asset
+    pub encoding_cache_control: BTreeMap<String, String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            update_interval_secs: DEFAULT_UPDATE_INTERVAL_SECS,
+            asset_headers: BTreeMap::new(),
+            content_security_policy: DEFAULT_CONTENT_SECURITY_POLICY.to_string(),
+            immutable_max_age_secs: DEFAULT_IMMUTABLE_MAX_AGE_SECS,
+            history_length: MAX_HISTORY_LENGTH,
+            encodings: vec!["br".to_string(), "gzip".to_string()],
+            number_separator: ",".to_string(),
+            indian_grouping: false,
+            display_offset_secs: 0,
+            cors_allowed_origins: vec![],
+            redirects: BTreeMap::new(),
+            spa_mode: false,
+            gzip_level: DEFAULT_GZIP_LEVEL,
+            brotli_quality: DEFAULT_BROTLI_QUALITY,
+            maintenance_retry_after_secs: None,
+            log_verbose: true,
+            hsts_max_age_secs: HSTS_PRELOAD_MIN_MAX_AGE_SECS,
+            hsts_include_subdomains: true,
+            hsts_preload: false,
+            count_requests: false,
+            permissions_policy: DEFAULT_PERMISSIONS_POLICY.to_string(),
+            low_cycles_threshold: DEFAULT_LOW_CYCLES_THRESHOLD,
+            html_cache_control: DEFAULT_HTML_CACHE_CONTROL.to_string(),
+            metrics_auth: None,
+            download_patterns: vec![],
+            header_exclusions: vec![],
+            frame_ancestors: vec![],
+            debug_headers: false,
+            aliases: BTreeMap::new(),
+            alias_redirects: BTreeMap::new(),
+            base_path: String::new(),
+            max_body_bytes: 0,
+            fallbacks: BTreeMap::new(),
+            prefer_gzip: false,
+            security_contact: None,
+            encoding_cache_control: BTreeMap::new(),
+        }
+    }
+}
+
+pub(crate) fn with_config<R>(f: impl FnOnce(&Config) -> R) -> R {
+    CONFIG.with_borrow(f)
+}
+
+pub(crate) fn update_config(f: impl FnOnce(&mut Config)) {
+    CONFIG.with_borrow_mut(f)
+}
+
+pub(crate) fn snapshot() -> Config {
+    CONFIG.with_borrow(|config| config.clone())
+}
+
+pub(crate) fn replace(config: Config) {
+    CONFIG.set(config);
+}
+
+// Small enough to only be written to stable memory in pre_upgrade
+pub(crate) fn save() {
+    let bytes = serde_json::to_vec(&snapshot())
+        .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to encode the config: {}", err)));
+    if let Err(err) = config_cell().set(bytes) {
+        ic_cdk::trap(&format!("Failed to save the config: {:?}", err));
+    }
+}
+
+// Keeps the defaults when nothing was saved yet. An unreadable config traps, rather than
+// silently replacing the one that was set.
+pub(crate) fn restore() {
+    let cell = config_cell();
+    if cell.get().is_empty() {
+        return;
+    }
+    match serde_json::from_slice::<Config>(cell.get()) {
+        Ok(config) => replace(config),
+        Err(err) => ic_cdk::trap(&format!("Failed to restore the config: {}", err)),
+    }
+}
+
+fn config_cell() -> StableCell<Vec<u8>, Memory> {
+    StableCell::init(memory::config_memory(), Vec::new())
+        .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to open the config: {}", err)))
+}
+
+pub(crate) fn is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("Caller is not a controller".to_string())
+    }
+}
+
+// An empty header list removes the override for the pattern
+#[ic_cdk::update(guard = "is_controller")]
+fn set_asset_headers(pattern: String, headers: Vec<(String, String)>) -> Result<(), String> {
+    Glob::new(&pattern).map_err(|err| format!("Invalid pattern {}: {}", pattern, err))?;
+    if let Some((name, _)) = headers.iter().find(|(name, _)| name.trim().is_empty()) {
+        return Err(format!("Invalid header name {:?}", name));
+    }
+
+    let headers: Vec<(String, String)> = headers
+        .into_iter()
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value))
+        .collect();
+    update_config(|config| {
+        if headers.is_empty() {
+            config.asset_headers.remove(&pattern);
+        } else {
+            config.asset_headers.insert(pattern, headers);
+        }
+    });
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn list_asset_headers() -> Vec<(String, Vec<(String, String)>)> {
+    with_config(|config| config.asset_headers.clone().into_iter().collect())
+}
+
+// Matching assets get a content-disposition named after their file name, unless
+// set_asset_headers already sets one for them
+#[ic_cdk::update(guard = "is_controller")]
+fn set_download_patterns(patterns: Vec<String>) -> Result<(), String> {
+    for pattern in &patterns {
+        Glob::new(pattern).map_err(|err| format!("Invalid pattern {}: {}", pattern, err))?;
+    }
+    update_config(|config| config.download_patterns = patterns);
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_download_patterns() -> Vec<String> {
+    with_config(|config| config.download_patterns.clone())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn set_header_exclusions(patterns: Vec<String>) -> Result<(), String> {
+    for pattern in &patterns {
+        Glob::new(pattern).map_err(|err| format!("Invalid pattern {}: {}", pattern, err))?;
+    }
+    update_config(|config| config.header_exclusions = patterns);
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_header_exclusions() -> Vec<String> {
+    with_config(|config| config.header_exclusions.clone())
+}
+
+// 0 turns the limit off
+#[ic_cdk::update(guard = "is_controller")]
+fn set_max_body_bytes(max_body_bytes: u64) {
+    update_config(|config| config.max_body_bytes = max_body_bytes);
+}
+
+#[ic_cdk::query]
+fn get_max_body_bytes() -> u64 {
+    with_config(|config| config.max_body_bytes)
+}
+
+// A bundled or uploaded .well-known/security.txt takes precedence
+#[ic_cdk::update(guard = "is_controller")]
+fn set_security_contact(contact: String) -> Result<(), String> {
+    let contact = contact.trim().to_string();
+    if contact.is_empty() {
+        update_config(|config| config.security_contact = None);
+    } else {
+        if !["mailto:", "https://", "tel:"]
+            .iter()
+            .any(|scheme| contact.starts_with(scheme))
+        {
+            return Err(format!(
+                "Contact {} must be a mailto:, https:// or tel: URI",
+                contact
+            ));
+        }
+        validate_header_value(&contact)?;
+        update_config(|config| config.security_contact = Some(contact));
+    }
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_security_contact() -> Option<String> {
+    with_config(|config| config.security_contact.clone())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn set_csp(policy: String) -> Result<(), String> {
+    validate_header_value(&policy)?;
+    update_config(|config| config.content_security_policy = policy);
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_csp() -> String {
+    with_config(|config| config.content_security_policy.clone())
+}
+
+// Overrides the frame-ancestors directive of the CSP. X-Frame-Options can't list origins,
+// so it's only sent while embedding is denied or limited to 'self'.
+#[ic_cdk::update(guard = "is_controller")]
+fn set_frame_ancestors(ancestors: Vec<String>) -> Result<(), String> {
+    if let Some(invalid) = ancestors
+        .iter()
+        .find(|ancestor| *ancestor != "'self'" && !is_frame_origin(ancestor))
+    {
+        return Err(format!("Invalid frame ancestor {}", invalid));
+    }
+    update_config(|config| config.frame_ancestors = ancestors);
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_frame_ancestors() -> Vec<String> {
+    with_config(|config| config.frame_ancestors.clone())
+}
+
+// A scheme and host, optionally with a leading wildcard label and a port
+fn is_frame_origin(origin: &str) -> bool {
+    let Some(host) = origin
+        .strip_prefix("https://")
+        .or_else(|| origin.strip_prefix("http://"))
+    else {
+        return false;
+    };
+    let (host, port) = host.split_once(':').unwrap_or((host, ""));
+    let host = host.strip_prefix("*.").unwrap_or(host);
+    !host.is_empty()
+        && host.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        && (port.is_empty() || port.parse::<u16>().is_ok())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn set_permissions_policy(policy: String) -> Result<(), String> {
+    validate_header_value(&policy)?;
+    update_config(|config| config.permissions_policy = policy);
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_permissions_policy() -> String {
+    with_config(|config| config.permissions_policy.clone())
+}
+
+// The status page, status JSON and metrics flag balances below the threshold
+#[ic_cdk::update(guard = "is_controller")]
+fn set_low_cycles_threshold(threshold: u64) {
+    update_config(|config| config.low_cycles_threshold = threshold);
+    crate::invalidate_render_cache();
+    crate::certify_helper(true);
+}
+
+#[ic_cdk::query]
+fn get_low_cycles_threshold() -> u64 {
+    with_config(|config| config.low_cycles_threshold)
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn set_immutable_max_age(secs: u64) {
+    update_config(|config| config.immutable_max_age_secs = secs);
+    crate::certify_helper(false);
+}
+
+#[ic_cdk::query]
+fn get_immutable_max_age() -> u64 {
+    with_config(|config| config.immutable_max_age_secs)
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn set_html_cache_control(value: String) -> Result<(), String> {
+    validate_cache_control(&value)?;
+    update_config(|config| config.html_cache_control = value);
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_html_cache_control() -> String {
+    with_config(|config| config.html_cache_control.clone())
+}
+
+// None makes the status page public again. The page is still certified as usual, next to
+// the 401 while credentials are set, and then the queries returning the same status are
+// controllers only.
+#[ic_cdk::update(guard = "is_controller")]
+fn set_metrics_auth(credentials: Option<(String, String)>) -> Result<(), String> {
+    let auth = match credentials {
+        Some((username, password)) => {
+            if username.is_empty() || username.contains(':') {
+                return Err("Username must be non-empty and must not contain ':'".to_string());
+            }
+            if password.is_empty() {
+                return Err("Password must not be empty".to_string());
+            }
+            let hash = crate::auth::credentials_hash(&username, &password);
+            Some((username, hash))
+        }
+        None => None,
+    };
+    update_config(|config| config.metrics_auth = auth);
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::query(guard = "is_controller")]
+fn get_metrics_auth_username() -> Option<String> {
+    with_config(|config| {
+        config
+            .metrics_auth
+            .as_ref()
+            .map(|(username, _)| username.clone())
+    })
+}
+
+// Values above the IC maximum are capped
+#[ic_cdk::update(guard = "is_controller")]
+fn set_history_length(n: u32) {
+    update_config(|config| config.history_length = u64::from(n).min(MAX_HISTORY_LENGTH));
+    crate::invalidate_render_cache();
+    crate::certify_helper(true);
+}
+
+#[ic_cdk::query]
+fn get_history_length() -> u32 {
+    with_config(|config| config.history_length as u32)
+}
+
+// Encodings in order of preference, identity is always served as a fallback
+#[ic_cdk::update(guard = "is_controller")]
+fn set_encodings(encodings: Vec<String>) -> Result<(), String> {
+    if let Some(unknown) = encodings.iter().find(|name| parse_encoding(name).is_none()) {
+        return Err(format!("Unknown encoding {}", unknown));
+    }
+    update_config(|config| config.encodings = encodings);
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_encodings() -> Vec<String> {
+    with_config(|config| config.encodings.clone())
+}
+
+// For proxies that corrupt brotli, serves gzip to clients accepting both
+#[ic_cdk::update(guard = "is_controller")]
+fn set_prefer_gzip(enabled: bool) {
+    update_config(|config| config.prefer_gzip = enabled);
+    crate::certify_helper(false);
+}
+
+#[ic_cdk::query]
+fn get_prefer_gzip() -> bool {
+    with_config(|config| config.prefer_gzip)
+}
+
+// None serves the variants of the encoding with the cache-control of their asset again
+#[ic_cdk::update(guard = "is_controller")]
+fn set_encoding_cache_control(encoding: String, value: Option<String>) -> Result<(), String> {
+    let Some(name) = parse_encoding(&encoding).map(|encoding| encoding.to_string()) else {
+        return Err(format!("Unknown encoding {}", encoding));
+    };
+    if let Some(value) = &value {
+        validate_cache_control(value)?;
+    }
+    update_config(|config| match value {
+        Some(value) => {
+            config.encoding_cache_control.insert(name, value);
+        }
+        None => {
+            config.encoding_cache_control.remove(&name);
+        }
+    });
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn list_encoding_cache_control() -> Vec<(String, String)> {
+    with_config(|config| config.encoding_cache_control.clone().into_iter().collect())
+}
+
+// Higher levels compress better but cost more cycles on every certification
+#[ic_cdk::update(guard = "is_controller")]
+fn set_gzip_level(level: u8) -> Result<(), String> {
+    if level > MAX_GZIP_LEVEL {
+        return Err(format!("Gzip level must be at most {}", MAX_GZIP_LEVEL));
+    }
+    update_config(|config| config.gzip_level = level);
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn set_brotli_quality(quality: u8) -> Result<(), String> {
+    if quality > MAX_BROTLI_QUALITY {
+        return Err(format!(
+            "Brotli quality must be at most {}",
+            MAX_BROTLI_QUALITY
+        ));
+    }
+    update_config(|config| config.brotli_quality = quality);
+    crate::certify_helper(false);
+    Ok(())
+}
+
+// (gzip level, brotli quality)
+#[ic_cdk::query]
+fn get_compression_levels() -> (u8, u8) {
+    with_config(|config| (config.gzip_level, config.brotli_quality))
+}
+
+// Every path except /healthz is answered with the maintenance page and a 503
+#[ic_cdk::update(guard = "is_controller")]
+fn set_maintenance(enabled: bool, retry_after_secs: u32) {
+    update_config(|config| {
+        config.maintenance_retry_after_secs = enabled.then_some(retry_after_secs)
+    });
+    crate::certify_helper(false);
+}
+
+#[ic_cdk::query]
+fn get_maintenance() -> Option<u32> {
+    with_config(|config| config.maintenance_retry_after_secs)
+}
+
+// Adds informational headers, such as the certification version, to every asset
+#[ic_cdk::update(guard = "is_controller")]
+fn set_debug_headers(enabled: bool) {
+    update_config(|config| config.debug_headers = enabled);
+    crate::certify_helper(false);
+}
+
+#[ic_cdk::query]
+fn get_debug_headers() -> bool {
+    with_config(|config| config.debug_headers)
+}
+
+// Logs every certification run instead of only the failed ones
+#[ic_cdk::update(guard = "is_controller")]
+fn set_log_verbose(verbose: bool) {
+    update_config(|config| config.log_verbose = verbose);
+}
+
+#[ic_cdk::query]
+fn get_log_verbose() -> bool {
+    with_config(|config| config.log_verbose)
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn set_hsts(max_age: u64, include_subdomains: bool, preload: bool) -> Result<(), String> {
+    if preload && (max_age < HSTS_PRELOAD_MIN_MAX_AGE_SECS || !include_subdomains) {
+        return Err(format!(
+            "Preload requires includeSubDomains and a max-age of at least {}",
+            HSTS_PRELOAD_MIN_MAX_AGE_SECS
+        ));
+    }
+    update_config(|config| {
+        config.hsts_max_age_secs = max_age;
+        config.hsts_include_subdomains = include_subdomains;
+        config.hsts_preload = preload;
+    });
+    crate::certify_helper(false);
+    Ok(())
+}
+
+// (max-age, includeSubDomains, preload)
+#[ic_cdk::query]
+fn get_hsts() -> (u64, bool, bool) {
+    with_config(|config| {
+        (
+            config.hsts_max_age_secs,
+            config.hsts_include_subdomains,
+            config.hsts_preload,
+        )
+    })
+}
+
+// Upgrades every request to an update call so it can be counted
+#[ic_cdk::update(guard = "is_controller")]
+fn set_count_requests(enabled: bool) {
+    update_config(|config| config.count_requests = enabled);
+}
+
+#[ic_cdk::query]
+fn get_count_requests() -> bool {
+    with_config(|config| config.count_requests)
+}
+
+pub(crate) fn parse_encoding(name: &str) -> Option<AssetEncoding> {
+    match name.trim().to_ascii_lowercase().as_str() {
+        "br" | "brotli" => Some(AssetEncoding::Brotli),
+        "gzip" => Some(AssetEncoding::Gzip),
+        "deflate" => Some(AssetEncoding::Deflate),
+        _ => None,
+    }
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn set_number_format(separator: String, indian: bool) -> Result<(), String> {
+    if ![",", "_", " "].contains(&separator.as_str()) {
+        return Err(format!("Unsupported separator {:?}", separator));
+    }
+    update_config(|config| {
+        config.number_separator = separator;
+        config.indian_grouping = indian;
+    });
+    crate::invalidate_render_cache();
+    crate::certify_helper(true);
+    Ok(())
+}
+
+// Takes "+05:30" or "-08:00", and "UTC", "Z" or an empty offset for UTC
+#[ic_cdk::update(guard = "is_controller")]
+fn set_display_offset(offset: String) -> Result<(), String> {
+    let offset_secs = parse_offset(&offset)?;
+    update_config(|config| config.display_offset_secs = offset_secs);
+    crate::invalidate_render_cache();
+    crate::certify_helper(true);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_display_offset() -> String {
+    let offset_secs = with_config(|config| config.display_offset_secs);
+    if offset_secs == 0 {
+        return "UTC".to_string();
+    }
+    let sign = if offset_secs < 0 { '-' } else { '+' };
+    let minutes = offset_secs.unsigned_abs() / 60;
+    format!("{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+}
+
+fn parse_offset(offset: &str) -> Result<i32, String> {
+    let offset = offset.trim();
+    if offset.is_empty() || offset == "Z" || offset.eq_ignore_ascii_case("utc") {
+        return Ok(0);
+    }
+    let invalid = || format!("Invalid offset {:?}, expected e.g. +05:30", offset);
+    let (sign, rest) = if let Some(rest) = offset.strip_prefix('+') {
+        (1, rest)
+    } else if let Some(rest) = offset.strip_prefix('-') {
+        (-1, rest)
+    } else {
+        return Err(invalid());
+    };
+    let (hours, minutes) = rest.split_once(':').ok_or_else(invalid)?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return Err(invalid());
+    }
+    let hours: i32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+    if hours > 14 || minutes > 59 || (hours == 14 && minutes > 0) {
+        return Err(format!("Offset {} is out of range", offset));
+    }
+    Ok(sign * (hours * 3_600 + minutes * 60))
+}
+
+// An empty list disables CORS, "*" allows any origin
+#[ic_cdk::update(guard = "is_controller")]
+fn set_cors_allowed_origins(origins: Vec<String>) -> Result<(), String> {
+    if let Some(invalid) = origins.iter().find(|origin| {
+        *origin != "*" && !origin.starts_with("https://") && !origin.starts_with("http://")
+    }) {
+        return Err(format!("Invalid origin {}", invalid));
+    }
+    update_config(|config| config.cors_allowed_origins = origins);
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_cors_allowed_origins() -> Vec<String> {
+    with_config(|config| config.cors_allowed_origins.clone())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn add_redirect(from: String, to: String, permanent: bool) -> Result<(), String> {
+    if !from.starts_with('/') {
+        return Err(format!("Redirect source {} must start with /", from));
+    }
+    validate_header_value(&to)?;
+    update_config(|config| {
+        config.redirects.insert(from, (to, permanent));
+    });
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn remove_redirect(from: String) {
+    update_config(|config| {
+        config.redirects.remove(&from);
+    });
+    crate::certify_helper(false);
+}
+
+#[ic_cdk::query]
+fn list_redirects() -> Vec<(String, String, bool)> {
+    with_config(|config| {
+        config
+            .redirects
+            .iter()
+            .map(|(from, (to, permanent))| (from.clone(), to.clone(), *permanent))
+            .collect()
+    })
+}
+
+// Without a status, or with 200, the alias serves the certified response of the target,
+// e.g. /home for /index.html. A redirect status answers with a certified redirect to the
+// target instead, by the asset router for 301 and 307 and next to its responses otherwise.
+#[ic_cdk::update(guard = "is_controller")]
+fn add_alias(alias: String, target: String, status: Option<u16>) -> Result<(), String> {
+    if !alias.starts_with('/') || alias == "/" || alias.contains("//") || alias.ends_with('/') {
+        return Err(format!(
+            "Alias {} must start with /, without repeated or trailing slashes",
+            alias
+        ));
+    }
+    let redirecting = with_config(|config| config.alias_redirects.contains_key(&alias));
+    if crate::asset::is_certified(&alias) && !redirecting {
+        return Err(format!("Alias {} is already an asset or redirect", alias));
+    }
+    if !crate::asset::is_certified(&target) {
+        return Err(format!("No asset at {}", target));
+    }
+    match status.unwrap_or(200) {
+        200 => update_config(|config| {
+            config.alias_redirects.remove(&alias);
+            config.aliases.insert(alias, target);
+        }),
+        status @ (301 | 302 | 303 | 307 | 308) => {
+            let target = format!("/{}", target.trim_start_matches('/'));
+            update_config(|config| {
+                config.aliases.remove(&alias);
+                config.alias_redirects.insert(alias, (target, status));
+            })
+        }
+        status => return Err(format!("Unsupported alias status {}", status)),
+    }
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn remove_alias(alias: String) {
+    update_config(|config| {
+        config.aliases.remove(&alias);
+        config.alias_redirects.remove(&alias);
+    });
+    crate::certify_helper(false);
+}
+
+// (alias, target, redirect status), transparent aliases have no status
+#[ic_cdk::query]
+fn list_aliases() -> Vec<(String, String, Option<u16>)> {
+    with_config(|config| {
+        let aliases = config
+            .aliases
+            .iter()
+            .map(|(alias, target)| (alias.clone(), target.clone(), None));
+        let redirects = config
+            .alias_redirects
+            .iter()
+            .map(|(alias, (target, status))| (alias.clone(), target.clone(), Some(*status)));
+        aliases.chain(redirects).collect()
+    })
+}
+
+// Redirects, aliases and the language redirect are all moved under the base path too
+#[ic_cdk::update(guard = "is_controller")]
+fn set_base_path(base_path: String) -> Result<(), String> {
+    let valid = base_path.is_empty()
+        || (base_path.starts_with('/')
+            && !base_path.ends_with('/')
+            && !base_path.contains("//")
+            && base_path
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "/-_.~".contains(c)));
+    if !valid {
+        return Err(format!(
+            "Base path {} must be empty or start with / without a trailing one",
+            base_path
+        ));
+    }
+    update_config(|config| config.base_path = base_path);
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_base_path() -> String {
+    with_config(|config| config.base_path.clone())
+}
+
+// The page is served for every path under the scope without a more specific asset or
+// fallback, e.g. /admin/missing gets admin-404.html for the /admin scope
+#[ic_cdk::update(guard = "is_controller")]
+fn add_fallback(scope: String, page_path: String, status: u16) -> Result<(), String> {
+    if !scope.starts_with('/') || scope == "/" || scope.ends_with('/') {
+        return Err(format!(
+            "Scope {} must start with /, without a trailing one and not be the root",
+            scope
+        ));
+    }
+    if !page_path.ends_with(".html") || !crate::asset::is_certified(&page_path) {
+        return Err(format!("No HTML page at {}", page_path));
+    }
+    ic_http_certification::StatusCode::from_u16(status)
+        .map_err(|_| format!("Invalid status code {}", status))?;
+    let page_path = page_path.trim_start_matches('/').to_string();
+    update_config(|config| {
+        config.fallbacks.insert(scope, (page_path, status));
+    });
+    crate::certify_helper(false);
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn remove_fallback(scope: String) {
+    update_config(|config| {
+        config.fallbacks.remove(&scope);
+    });
+    crate::certify_helper(false);
+}
+
+#[ic_cdk::query]
+fn list_fallbacks() -> Vec<(String, String, u16)> {
+    with_config(|config| {
+        config
+            .fallbacks
+            .iter()
+            .map(|(scope, (page_path, status))| (scope.clone(), page_path.clone(), *status))
+            .collect()
+    })
+}
+
+// Serves index.html for unknown paths without a file extension
+#[ic_cdk::update(guard = "is_controller")]
+fn set_spa_mode(enabled: bool) {
+    update_config(|config| config.spa_mode = enabled);
+    crate::certify_helper(false);
+}
+
+#[ic_cdk::query]
+fn get_spa_mode() -> bool {
+    with_config(|config| config.spa_mode)
+}
+
+// Comma separated directives, each a token with an optional =value,
+// e.g. "public, max-age=60, stale-while-revalidate=30"
+fn validate_cache_control(value: &str) -> Result<(), String> {
+    validate_header_value(value)?;
+    for directive in value.split(',').map(str::trim) {
+        let (name, argument) = match directive.split_once('=') {
+            Some((name, argument)) => (name.trim(), Some(argument.trim())),
+            None => (directive, None),
+        };
+        let is_token =
+            |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if !is_token(name) {
+            return Err(format!("Invalid cache-control directive {:?}", directive));
+        }
+        match argument {
+            Some(argument)
+                if argument.starts_with('"')
+                    && (argument.len() < 2 || !argument.ends_with('"')) =>
+            {
+                return Err(format!("Unterminated quoted value in {:?}", directive));
+            }
+            Some(argument) if !argument.starts_with('"') && !is_token(argument) => {
+                return Err(format!("Invalid cache-control value in {:?}", directive));
+            }
+            _ => {}
+        }
+        let takes_seconds = matches!(
+            name.to_ascii_lowercase().as_str(),
+            "max-age" | "s-maxage" | "stale-while-revalidate" | "stale-if-error"
+        );
+        if takes_seconds && argument.and_then(|a| a.parse::<u64>().ok()).is_none() {
+            return Err(format!("{} requires a number of seconds", name));
+        }
+    }
+    Ok(())
+}
+
+fn validate_header_value(value: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err("Value must not be empty".to_string());
+    }
+    if value.len() > MAX_HEADER_VALUE_LEN {
+        return Err(format!(
+            "Value must not be longer than {} bytes",
+            MAX_HEADER_VALUE_LEN
+        ));
+    }
+    if value.chars().any(char::is_control) {
+        return Err("Value must not contain control characters".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_fields_get_their_defaults() {
+        let config: Config =
+            serde_json::from_str(r#"{"update_interval_secs": 60, "removed_field": true}"#).unwrap();
+        assert_eq!(config.update_interval_secs, 60);
+        assert_eq!(config.gzip_level, DEFAULT_GZIP_LEVEL);
+        assert_eq!(config.html_cache_control, DEFAULT_HTML_CACHE_CONTROL);
+    }
+
+    #[test]
+    fn saved_config_is_restored() {
+        CONFIG.with_borrow_mut(|config| config.update_interval_secs = 60);
+        save();
+        replace(Config::default());
+        restore();
+        assert_eq!(with_config(|config| config.update_interval_secs), 60);
+    }
+
+    #[test]
+    fn parse_offset_accepts_utc_and_signed_offsets() {
+        assert_eq!(parse_offset(""), Ok(0));
+        assert_eq!(parse_offset("Z"), Ok(0));
+        assert_eq!(parse_offset("utc"), Ok(0));
+        assert_eq!(parse_offset("+05:30"), Ok(19_800));
+        assert_eq!(parse_offset("-03:00"), Ok(-10_800));
+        assert_eq!(parse_offset("+14:00"), Ok(50_400));
+    }
+
+    #[test]
+    fn parse_offset_rejects_malformed_or_out_of_range_offsets() {
+        for offset in [
+            "05:30", "+5:30", "+0530", "+05:3", "+aa:00", "+14:30", "+15:00", "-00:60",
+        ] {
+            assert!(parse_offset(offset).is_err(), "{}", offset);
+        }
+    }
+}
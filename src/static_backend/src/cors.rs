@@ -0,0 +1,91 @@
+use ic_http_certification::{HeaderField, HttpRequest, HttpResponse, StatusCode};
+
+use crate::asset::get_header;
+use crate::config;
+
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+const PREFLIGHT_MAX_AGE_SECS: u64 = 86_400;
+const ALLOW_ORIGIN: &str = "access-control-allow-origin";
+
+// A single origin, or any, doesn't depend on the request, so it's certified along with
+// every response
+pub(crate) fn certified_headers() -> Vec<HeaderField> {
+    config::with_config(|config| match config.cors_allowed_origins.as_slice() {
+        origins if origins.iter().any(|allowed| allowed == "*") => {
+            vec![(ALLOW_ORIGIN.to_string(), "*".to_string())]
+        }
+        [origin] => vec![(ALLOW_ORIGIN.to_string(), origin.clone())],
+        _ => vec![],
+    })
+}
+
+// With several origins allowed, the request's origin is echoed back when it's one of them.
+// That can't be certified, so only an update call can answer it.
+pub(crate) fn echoes_origin(req: &HttpRequest) -> bool {
+    echoed_origin(req).is_some()
+}
+
+fn echoed_origin(req: &HttpRequest) -> Option<String> {
+    let origin = get_header(req.headers(), "origin")?;
+    config::with_config(|config| {
+        let origins = &config.cors_allowed_origins;
+        (origins.len() > 1
+            && !origins.iter().any(|allowed| allowed == "*")
+            && origins.iter().any(|allowed| allowed == origin))
+        .then(|| origin.to_string())
+    })
+}
+
+fn cors_headers(origin: String) -> Vec<HeaderField> {
+    vec![
+        (ALLOW_ORIGIN.to_string(), origin),
+        ("vary".to_string(), "origin".to_string()),
+    ]
+}
+
+pub(crate) fn preflight(req: &HttpRequest) -> HttpResponse<'static> {
+    let mut headers = vec![
+        (
+            "access-control-allow-methods".to_string(),
+            ALLOWED_METHODS.to_string(),
+        ),
+        (
+            "access-control-allow-headers".to_string(),
+            get_header(req.headers(), "access-control-request-headers")
+                .unwrap_or("*")
+                .to_string(),
+        ),
+        (
+            "access-control-max-age".to_string(),
+            PREFLIGHT_MAX_AGE_SECS.to_string(),
+        ),
+    ];
+    match echoed_origin(req) {
+        Some(origin) => headers.extend(cors_headers(origin)),
+        None => headers.extend(certified_headers()),
+    }
+
+    HttpResponse::builder()
+        .with_status_code(StatusCode::NO_CONTENT)
+        .with_headers(headers)
+        .build()
+}
+
+// Only called from update calls, whose responses don't need to be certified
+pub(crate) fn with_cors_headers(
+    req: &HttpRequest,
+    response: HttpResponse<'static>,
+) -> HttpResponse<'static> {
+    let Some(origin) = echoed_origin(req) else {
+        return response;
+    };
+
+    let mut headers = response.headers().to_vec();
+    headers.retain(|(name, _)| !name.eq_ignore_ascii_case(ALLOW_ORIGIN));
+    headers.extend(cors_headers(origin));
+    HttpResponse::builder()
+        .with_status_code(response.status_code())
+        .with_headers(headers)
+        .with_body(response.body().to_vec())
+        .build()
+}
@@ -0,0 +1,355 @@
+use candid::Principal;
+use handlebars::{handlebars_helper, Handlebars};
+use ic_cdk::api::management_canister::main::{CanisterChange, CanisterStatusType};
+use num_format::{Buffer, CustomFormat, Grouping};
+use serde::Serialize;
+use std::fmt::Debug;
+
+use crate::config;
+use crate::timestamp;
+
+handlebars_helper!(toJSON: |value: CanisterChange| pretty_json(&value));
+// Serializing a JSON value can't fail, and keeps the rendered page small
+handlebars_helper!(toJSONCompact: |value: Json| value.to_string());
+handlebars_helper!(toNumLocale: |value: u64| format_number(value));
+handlebars_helper!(toNumUnit: |value: u64, unit: str| number_with_unit(value, unit));
+handlebars_helper!(toBytesHuman: |value: u64| bytes_human(value));
+handlebars_helper!(toCyclesHuman: |value: f64| cycles_human(value));
+handlebars_helper!(toPercent: |value: u64| percent(value));
+handlebars_helper!(toSignedNum: |value: Json| signed_number(value));
+handlebars_helper!(toHexShort: |value: str| hex_short(value));
+handlebars_helper!(toDurationHuman: |value: u64| duration_human(value));
+handlebars_helper!(toRelativeTime: |value: u64| relative_time(value, ic_cdk::api::time()));
+handlebars_helper!(toDateTime: |value: u64| timestamp(value));
+handlebars_helper!(toStatusBadge: |value: CanisterStatusType| status_badge(value));
+handlebars_helper!(toDashboardLink: |value: str, {kind: str = "auto"}| dashboard_link(value, kind));
+
+pub(crate) fn register_helpers(handlebars: &mut Handlebars) {
+    handlebars.register_helper("toJSON", Box::new(toJSON));
+    handlebars.register_helper("toJSONCompact", Box::new(toJSONCompact));
+    handlebars.register_helper("toNumLocale", Box::new(toNumLocale));
+    handlebars.register_helper("toNumUnit", Box::new(toNumUnit));
+    handlebars.register_helper("toBytesHuman", Box::new(toBytesHuman));
+    handlebars.register_helper("toCyclesHuman", Box::new(toCyclesHuman));
+    handlebars.register_helper("toPercent", Box::new(toPercent));
+    handlebars.register_helper("toDurationHuman", Box::new(toDurationHuman));
+    handlebars.register_helper("toHexShort", Box::new(toHexShort));
+    handlebars.register_helper("toSignedNum", Box::new(toSignedNum));
+    handlebars.register_helper("toRelativeTime", Box::new(toRelativeTime));
+    handlebars.register_helper("toDateTime", Box::new(toDateTime));
+    handlebars.register_helper("toStatusBadge", Box::new(toStatusBadge));
+    handlebars.register_helper("toDashboardLink", Box::new(toDashboardLink));
+}
+
+// A value that can't be serialized renders empty rather than failing the whole page
+fn pretty_json<T: Serialize + Debug>(value: &T) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_else(|err| {
+        crate::log(&format!("Failed to serialize {:?}: {}", value, err));
+        String::new()
+    })
+}
+
+fn format_number(value: u64) -> String {
+    let (separator, indian) =
+        config::with_config(|config| (config.number_separator.clone(), config.indian_grouping));
+    let grouping = if indian {
+        Grouping::Indian
+    } else {
+        Grouping::Standard
+    };
+    let Ok(format) = CustomFormat::builder()
+        .grouping(grouping)
+        .separator(separator)
+        .build()
+    else {
+        return value.to_string();
+    };
+    let mut buf = Buffer::new();
+    buf.write_formatted(&value, &format);
+    buf.to_string()
+}
+
+// Takes the singular unit, e.g. {{toNumUnit last_cycles_cost "cycle"}}
+fn number_with_unit(value: u64, unit: &str) -> String {
+    let plural = if value == 1 { "" } else { "s" };
+    format!("{} {}{}", format_number(value), unit, plural)
+}
+
+fn bytes_human(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, UNITS[unit])
+}
+
+// Takes a float as balances above u64 are serialized as one
+fn cycles_human(cycles: f64) -> String {
+    const UNITS: [(f64, &str); 3] = [(1e12, "T"), (1e9, "B"), (1e6, "M")];
+    for (scale, unit) in UNITS {
+        if cycles >= scale {
+            return format!("{:.2} {} cycles", cycles / scale, unit);
+        }
+    }
+    format!("{} cycles", cycles)
+}
+
+// A zero allocation means the canister is scheduled on a best-effort basis
+fn percent(value: u64) -> String {
+    if value == 0 {
+        "best-effort".to_string()
+    } else {
+        format!("{}%", value)
+    }
+}
+
+// Returns markup, so it's rendered unescaped with {{{toStatusBadge status}}}
+fn status_badge(status: CanisterStatusType) -> String {
+    // No wildcard, a new status has to be given a badge
+    let (label, class) = match status {
+        CanisterStatusType::Running => ("Running", "badge-green"),
+        CanisterStatusType::Stopping => ("Stopping", "badge-yellow"),
+        CanisterStatusType::Stopped => ("Stopped", "badge-red"),
+    };
+    format!("<span class=\"badge {}\">{}</span>", class, label)
+}
+
+// The kind is "canister", "account" or "auto", which tells canister ids apart by their
+// opaque id class. Returns markup, e.g. {{{toDashboardLink this kind="canister"}}}.
+fn dashboard_link(principal: &str, kind: &str) -> String {
+    let is_canister = match kind {
+        "canister" => true,
+        "account" => false,
+        _ => Principal::from_text(principal)
+            .map(|principal| principal.as_slice().last() == Some(&0x01))
+            .unwrap_or(false),
+    };
+    let path = if is_canister { "canister" } else { "account" };
+    format!(
+        "<a href=\"https://dashboard.internetcomputer.org/{}/{}\">{}</a>",
+        path,
+        handlebars::html_escape(principal),
+        handlebars::html_escape(principal)
+    )
+}
+
+// Always signed, e.g. +1,234 or -500. Missing values render as a dash.
+fn signed_number(value: &serde_json::Value) -> String {
+    match value.as_i64() {
+        Some(value) if value < 0 => format!("-{}", format_number(value.unsigned_abs())),
+        Some(value) => format!("+{}", format_number(value as u64)),
+        None => "\u{2014}".to_string(),
+    }
+}
+
+// The first and last 6 characters, values of up to 12 are kept whole
+fn hex_short(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    if chars.len() <= 12 {
+        return value.to_string();
+    }
+    let head: String = chars[..6].iter().collect();
+    let tail: String = chars[chars.len() - 6..].iter().collect();
+    format!("{}\u{2026}{}", head, tail)
+}
+
+// In the largest whole unit, e.g. 2592000 is "30 days"
+fn duration_human(secs: u64) -> String {
+    const UNITS: [(u64, &str); 5] = [
+        (31_536_000, "year"),
+        (86_400, "day"),
+        (3_600, "hour"),
+        (60, "minute"),
+        (1, "second"),
+    ];
+    match UNITS.into_iter().find(|(scale, _)| secs >= *scale) {
+        Some((scale, unit)) => number_with_unit(secs / scale, unit),
+        None => number_with_unit(0, "second"),
+    }
+}
+
+// Both timestamps are in nanoseconds, a zero timestamp is treated as unset
+fn relative_time(time: u64, now: u64) -> String {
+    const UNITS: [(u64, &str); 4] = [
+        (86_400, "day"),
+        (3_600, "hour"),
+        (60, "minute"),
+        (1, "second"),
+    ];
+    if time == 0 {
+        return "never".to_string();
+    }
+
+    let secs = time.abs_diff(now) / 1_000_000_000;
+    let Some((scale, unit)) = UNITS.into_iter().find(|(scale, _)| secs >= *scale) else {
+        return "just now".to_string();
+    };
+    let count = secs / scale;
+    let plural = if count == 1 { "" } else { "s" };
+    if time > now {
+        format!("in {} {}{}", count, unit, plural)
+    } else {
+        format!("{} {}{} ago", count, unit, plural)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_human_switches_units_at_1024() {
+        assert_eq!(bytes_human(0), "0 B");
+        assert_eq!(bytes_human(1023), "1023 B");
+        assert_eq!(bytes_human(1024), "1.00 KiB");
+        assert_eq!(bytes_human(1024 * 1024 - 1), "1024.00 KiB");
+        assert_eq!(bytes_human(1024 * 1024), "1.00 MiB");
+        assert_eq!(bytes_human(3 * 1024 * 1024 * 1024 / 2), "1.50 GiB");
+        assert_eq!(bytes_human(u64::MAX), "16777216.00 TiB");
+    }
+
+    #[test]
+    fn cycles_human_picks_the_largest_unit() {
+        assert_eq!(cycles_human(0.0), "0 cycles");
+        assert_eq!(cycles_human(999_999.0), "999999 cycles");
+        assert_eq!(cycles_human(1e6), "1.00 M cycles");
+        assert_eq!(cycles_human(2.5e9), "2.50 B cycles");
+        assert_eq!(cycles_human(1.234e13), "12.34 T cycles");
+    }
+
+    #[test]
+    fn duration_human_uses_the_largest_whole_unit() {
+        assert_eq!(duration_human(0), "0 seconds");
+        assert_eq!(duration_human(1), "1 second");
+        assert_eq!(duration_human(60), "1 minute");
+        assert_eq!(duration_human(3_600), "1 hour");
+        assert_eq!(duration_human(7_199), "1 hour");
+        assert_eq!(duration_human(86_400), "1 day");
+        assert_eq!(duration_human(2_592_000), "30 days");
+        assert_eq!(duration_human(u64::MAX), "584,942,417,355 years");
+    }
+
+    #[test]
+    fn hex_short_keeps_short_values_whole() {
+        assert_eq!(hex_short(""), "");
+        assert_eq!(hex_short("abc123"), "abc123");
+        assert_eq!(hex_short("abc123def456"), "abc123def456");
+        assert_eq!(hex_short("abc1230def456"), "abc123\u{2026}def456");
+        let module_hash = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        assert_eq!(hex_short(module_hash), "012345\u{2026}abcdef");
+    }
+
+    #[derive(Debug)]
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("not serializable"))
+        }
+    }
+
+    #[test]
+    fn pretty_json_renders_unserializable_values_empty() {
+        assert_eq!(pretty_json(&Unserializable), "");
+        assert_eq!(
+            pretty_json(&serde_json::json!({ "a": 1 })),
+            "{\n  \"a\": 1\n}"
+        );
+    }
+
+    #[test]
+    fn format_number_uses_the_configured_separator_and_grouping() {
+        for (separator, standard, indian) in [
+            (",", "1,234,567", "12,34,567"),
+            ("_", "1_234_567", "12_34_567"),
+            (" ", "1 234 567", "12 34 567"),
+        ] {
+            for (indian_grouping, expected) in [(false, standard), (true, indian)] {
+                config::update_config(|config| {
+                    config.number_separator = separator.to_string();
+                    config.indian_grouping = indian_grouping;
+                });
+                assert_eq!(format_number(1_234_567), expected);
+            }
+        }
+        assert_eq!(format_number(999), "999");
+    }
+
+    #[test]
+    fn number_with_unit_pluralizes_all_but_one() {
+        assert_eq!(number_with_unit(0, "cycle"), "0 cycles");
+        assert_eq!(number_with_unit(1, "cycle"), "1 cycle");
+        assert_eq!(number_with_unit(1_500, "cycle"), "1,500 cycles");
+    }
+
+    #[test]
+    fn relative_time_looks_both_ways() {
+        let now = 10_000 * 1_000_000_000;
+        assert_eq!(relative_time(0, now), "never");
+        assert_eq!(relative_time(now, now), "just now");
+        assert_eq!(relative_time(now - 1_000_000_000, now), "1 second ago");
+        assert_eq!(
+            relative_time(now - 7_200 * 1_000_000_000, now),
+            "2 hours ago"
+        );
+        assert_eq!(relative_time(now + 60 * 1_000_000_000, now), "in 1 minute");
+        assert_eq!(relative_time(now + 86_400 * 1_000_000_000, now), "in 1 day");
+    }
+
+    #[test]
+    fn status_badge_has_a_class_per_status() {
+        assert_eq!(
+            status_badge(CanisterStatusType::Running),
+            "<span class=\"badge badge-green\">Running</span>"
+        );
+        assert_eq!(
+            status_badge(CanisterStatusType::Stopping),
+            "<span class=\"badge badge-yellow\">Stopping</span>"
+        );
+        assert_eq!(
+            status_badge(CanisterStatusType::Stopped),
+            "<span class=\"badge badge-red\">Stopped</span>"
+        );
+    }
+
+    #[test]
+    fn dashboard_link_tells_canisters_from_accounts() {
+        let link = |path: &str, text: &str| {
+            format!(
+                "<a href=\"https://dashboard.internetcomputer.org/{}/{}\">{}</a>",
+                path, text, text
+            )
+        };
+        let ledger = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+        assert_eq!(dashboard_link(ledger, "auto"), link("canister", ledger));
+        assert_eq!(dashboard_link(ledger, "account"), link("account", ledger));
+        assert_eq!(
+            dashboard_link("2vxsx-fae", "auto"),
+            link("account", "2vxsx-fae")
+        );
+        assert_eq!(
+            dashboard_link("2vxsx-fae", "canister"),
+            link("canister", "2vxsx-fae")
+        );
+        assert_eq!(
+            dashboard_link("<not a principal>", "auto"),
+            link("account", "&lt;not a principal&gt;")
+        );
+    }
+
+    #[test]
+    fn signed_number_renders_missing_deltas_as_a_dash() {
+        assert_eq!(signed_number(&serde_json::json!(1_234)), "+1,234");
+        assert_eq!(signed_number(&serde_json::json!(0)), "+0");
+        assert_eq!(signed_number(&serde_json::json!(-500)), "-500");
+        // No previous balance on the first run
+        assert_eq!(signed_number(&serde_json::Value::Null), "\u{2014}");
+    }
+}
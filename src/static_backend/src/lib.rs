@@ -1,112 +1,675 @@
-use candid::{Nat, Principal};
+use candid::{CandidType, Nat, Principal};
 use chrono::prelude::*;
-use handlebars::{handlebars_helper, Handlebars};
+use handlebars::{Handlebars, Template};
 use ic_cdk::api::management_canister::main::{
     canister_info, canister_status, CanisterChange, CanisterInfoRequest, CanisterStatusResponse,
 };
 use ic_cdk::api::management_canister::main::{CanisterIdRecord, CanisterStatusType, LogVisibility};
-use ic_http_certification::{HttpRequest, HttpResponse};
+use ic_cdk_timers::TimerId;
+use ic_http_certification::{HttpRequest, HttpResponse, StatusCode};
 use include_dir::File;
-use num_format::{Buffer, CustomFormat, Grouping};
 use num_traits::cast::ToPrimitive;
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
 use std::time::Duration;
 
+use auth::is_status_reader;
+use config::is_controller;
+
 mod asset;
+mod auth;
+mod config;
+mod cors;
+mod helpers;
+mod memory;
+mod metrics;
+mod upload;
 
 thread_local! {
-    static LAST_CYCLES_FOR_TIMER: RefCell<u64> = RefCell::new(0);
+    static LAST_CYCLES_FOR_TIMER: RefCell<u64> = const { RefCell::new(0) };
+    // Cycle cost of the most recent runs, oldest first
+    static TIMER_COSTS: RefCell<VecDeque<u64>> = const { RefCell::new(VecDeque::new()) };
+    static COST_BREAKDOWN: RefCell<CostBreakdown> = RefCell::default();
+    // (status calls, rendering) cost of the run in progress, see asset::dynamic_assets
+    static PHASE_COSTS: RefCell<(u64, u64)> = const { RefCell::new((0, 0)) };
+    // (finished at in nanoseconds, duration in milliseconds) of the last certification run
+    static LAST_CERTIFICATION: RefCell<Option<(u64, u64)>> = const { RefCell::new(None) };
+    static TIMER_ID: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    static RENDER_CACHE: RefCell<Option<RenderCache>> = const { RefCell::new(None) };
+    static LAST_STATUS: RefCell<Option<DefiniteCanisterStatus>> = const { RefCell::new(None) };
+    // Start time in nanoseconds of the certification run in progress
+    static CERTIFYING: RefCell<Option<u64>> = const { RefCell::new(None) };
+    // Whether the run requested while another was in progress only covers dynamic assets
+    static CERTIFY_PENDING: RefCell<Option<bool>> = const { RefCell::new(None) };
+    // (path, error) of every bundled template that doesn't compile
+    static TEMPLATE_ERRORS: RefCell<Vec<(String, String)>> = const { RefCell::new(vec![]) };
+    // Only counted while request counting is enabled, see http_request_update
+    static REQUEST_COUNTS: RefCell<BTreeMap<String, u64>> = const { RefCell::new(BTreeMap::new()) };
+    static ACCESS_LOG: RefCell<VecDeque<RequestLogEntry>> = const { RefCell::new(VecDeque::new()) };
+    static CYCLES_HISTORY: RefCell<VecDeque<CyclesSample>> = const { RefCell::new(VecDeque::new()) };
+    // Consecutive runs that couldn't fetch the canister status
+    static STATUS_FAILURES: RefCell<u32> = const { RefCell::new(0) };
+    // Timer ticks left to skip before fetching the status again
+    static BACKOFF_TICKS: RefCell<u32> = const { RefCell::new(0) };
 }
 
 pub const ENABLE_TEMPLATING: bool = true;
-const UPDATE_INTERVAL_SECS: u64 = 120;
+// The page is re-rendered once the module or controllers change, or at least once per TTL
+const RENDER_CACHE_TTL_SECS: u64 = 600;
+const CERTIFY_TIMEOUT_SECS: u64 = 300;
+const CYCLES_HISTORY_CAPACITY: usize = 100;
+const TIMER_COSTS_CAPACITY: usize = 20;
+const WASM_PAGE_SIZE: u64 = 65_536;
+// About an hour at the default update interval
+const MAX_BACKOFF_TICKS: u32 = 31;
+// Bounds the counters, requests for any other path are counted together
+const MAX_COUNTED_PATHS: usize = 100;
+const OTHER_PATHS: &str = "other";
+const ACCESS_LOG_CAPACITY: usize = 100;
+// Below the 2 MiB reply limit, leaving room for the Candid encoding
+const MAX_ASSET_BYTES_REPLY: usize = 2_000_000;
+// The status page warns when the canister freezes sooner than this
+const LOW_RUNWAY_DAYS: u64 = 30;
 
-handlebars_helper!(toJSON: |value: CanisterChange| serde_json::to_string_pretty(&value).unwrap().to_string());
-handlebars_helper!(toNumLocale: |value: u64| {
-    let format = CustomFormat::builder().grouping(Grouping::Standard).separator("_").build().unwrap();
-    let mut buf = Buffer::new();
-    buf.write_formatted(&(value), &format);
-    buf.to_string()
-});
+struct RenderCache {
+    cached_at: u64,
+    status: DefiniteCanisterStatus,
+    rendered: Option<Vec<(String, String)>>,
+}
 
 #[ic_cdk::init]
 fn init() {
+    asset::record_deploy_time();
+    validate_templates();
     certify_on_timer();
 }
 
+// Uploads are kept in stable memory as they change, only the config is saved here
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    config::save();
+}
+
 #[ic_cdk::post_upgrade]
 fn post_upgrade() {
+    config::restore();
+    asset::record_deploy_time();
+    validate_templates();
     certify_on_timer();
 }
 
-fn certify_on_timer() {
-    fn certify_helper() {
-        ic_cdk::spawn(async {
-            let now = ic_cdk::api::performance_counter(1);
-            asset::certify_all_assets().await;
-            let elapsed = ic_cdk::api::performance_counter(1);
-
-            // instruction to cycles
-            // subnet size = 13 and ten_update_inst = 10 cycles
-            // cycles = inst * (10 / 10) * (13 / 13) = inst
-            LAST_CYCLES_FOR_TIMER.with_borrow_mut(|v| *v = elapsed - now);
+// Catches broken templates right after a deploy instead of on the first render.
+// Broken templates are still served, as their raw bytes.
+fn validate_templates() {
+    let (templates, partials) = asset::template_files();
+    let errors: Vec<(String, String)> = templates
+        .iter()
+        .chain(&partials)
+        .filter_map(|(_, file)| {
+            let source = String::from_utf8_lossy(file.contents());
+            let path = file.path().display().to_string();
+            Template::compile(&source)
+                .err()
+                .map(|err| (path, err.to_string()))
         })
+        .collect();
+    for (path, err) in &errors {
+        ic_cdk::println!("Template {} doesn't compile: {}", path, err);
     }
+    TEMPLATE_ERRORS.set(errors);
+}
 
+#[ic_cdk::query]
+fn template_errors() -> Vec<(String, String)> {
+    TEMPLATE_ERRORS.with_borrow(|errors| errors.clone())
+}
+
+fn certify_on_timer() {
     // Instantiate first timer immediately
     ic_cdk_timers::set_timer(Duration::from_secs(0), || {
-        certify_helper();
+        certify_helper(false);
     });
 
-    ic_cdk_timers::set_timer_interval(Duration::from_secs(UPDATE_INTERVAL_SECS), || {
-        certify_helper();
+    schedule_certify_timer();
+}
+
+// The recurring timer only re-certifies the assets rendered from the canister status,
+// bundled assets are certified once and again whenever their configuration changes.
+fn certify_helper(dynamic_only: bool) {
+    ic_cdk::spawn(async move {
+        if certify(dynamic_only).await.is_err() {
+            // Picked up by the running certification once it finishes, a full run wins
+            CERTIFY_PENDING.with_borrow_mut(|pending| {
+                *pending = Some(pending.unwrap_or(true) && dynamic_only)
+            });
+        }
+    })
+}
+
+async fn certify(dynamic_only: bool) -> Result<(), String> {
+    let started_at = ic_cdk::api::time();
+    if !try_start_certification(started_at) {
+        return Err("Certification is already in progress".to_string());
+    }
+
+    PHASE_COSTS.set((0, 0));
+    let now = ic_cdk::api::performance_counter(1);
+    let outcome = if dynamic_only {
+        asset::certify_dynamic_assets().await
+    } else {
+        asset::certify_all_assets().await
+    };
+    let elapsed = ic_cdk::api::performance_counter(1);
+    let finished_at = ic_cdk::api::time();
+    let duration_ms = Duration::from_nanos(finished_at.saturating_sub(started_at)).as_millis();
+    LAST_CERTIFICATION.set(Some((finished_at, duration_ms as u64)));
+
+    // instruction to cycles
+    // subnet size = 13 and ten_update_inst = 10 cycles
+    // cycles = inst * (10 / 10) * (13 / 13) = inst
+    let instructions = instructions_used(now, elapsed);
+    LAST_CYCLES_FOR_TIMER.with_borrow_mut(|v| *v = instructions);
+    record_timer_cost(instructions);
+    // Whatever isn't spent fetching and rendering the status goes to encoding and certifying
+    let (status_calls, rendering) = PHASE_COSTS.take();
+    COST_BREAKDOWN.set(CostBreakdown {
+        status_calls,
+        rendering,
+        certification: instructions.saturating_sub(status_calls + rendering),
     });
+    log_certification(dynamic_only, instructions, &outcome);
+    record_status_outcome(&outcome);
+
+    CERTIFYING.set(None);
+    if let Some(dynamic_only) = CERTIFY_PENDING.take() {
+        certify_helper(dynamic_only);
+    }
+    Ok(())
+}
+
+// Failures are always logged, successful runs only when verbose logging is enabled
+fn log_certification(dynamic_only: bool, instructions: u64, outcome: &Result<(), String>) {
+    let run = if dynamic_only { "dynamic" } else { "full" };
+    let assets = asset::certified_asset_count();
+    match outcome {
+        Ok(()) => {
+            if config::with_config(|config| config.log_verbose) {
+                ic_cdk::println!(
+                    "Certification ({} run) succeeded: {} assets, {} instructions",
+                    run,
+                    assets,
+                    instructions
+                );
+            }
+        }
+        Err(err) => ic_cdk::println!(
+            "Certification ({} run) kept the last render: {} assets, {} instructions: {}",
+            run,
+            assets,
+            instructions,
+            err
+        ),
+    }
+}
+
+// A run that traps after an await never clears the flag, so it expires after a timeout
+fn try_start_certification(now: u64) -> bool {
+    CERTIFYING.with_borrow_mut(|started_at| match *started_at {
+        Some(at) if now.saturating_sub(at) < CERTIFY_TIMEOUT_SECS * 1_000_000_000 => false,
+        _ => {
+            *started_at = Some(now);
+            true
+        }
+    })
+}
+
+fn record_status_outcome(outcome: &Result<(), String>) {
+    let failures = match outcome {
+        Ok(()) => 0,
+        Err(_) => STATUS_FAILURES.with_borrow(|failures| failures.saturating_add(1)),
+    };
+    STATUS_FAILURES.set(failures);
+    BACKOFF_TICKS.set(backoff_ticks(failures));
+}
+
+fn backoff_ticks(failures: u32) -> u32 {
+    1u32.checked_shl(failures)
+        .map_or(MAX_BACKOFF_TICKS, |ticks| ticks - 1)
+        .min(MAX_BACKOFF_TICKS)
+}
+
+fn skip_backoff_tick() -> bool {
+    BACKOFF_TICKS.with_borrow_mut(|ticks| {
+        let skip = *ticks > 0;
+        *ticks = ticks.saturating_sub(1);
+        skip
+    })
+}
+
+// (consecutive failures, timer ticks left to skip)
+#[ic_cdk::query(guard = "is_status_reader")]
+fn status_backoff() -> (u32, u32) {
+    (
+        STATUS_FAILURES.with_borrow(|v| *v),
+        BACKOFF_TICKS.with_borrow(|v| *v),
+    )
+}
+
+// Replaces the recurring timer, clearing the previous one so reconfiguring never leaks timers.
+// Every failure doubles the ticks skipped, up to MAX_BACKOFF_TICKS, and a success
+// resets them. Only the timer backs off, certify_now and config changes always run.
+fn schedule_certify_timer() {
+    let interval = config::with_config(|config| config.update_interval_secs);
+    let timer_id = ic_cdk_timers::set_timer_interval(Duration::from_secs(interval), || {
+        if !skip_backoff_tick() {
+            certify_helper(true);
+        }
+    });
+    if let Some(previous) = TIMER_ID.with_borrow_mut(|id| id.replace(timer_id)) {
+        ic_cdk_timers::clear_timer(previous);
+    }
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn set_update_interval(secs: u64) -> Result<(), String> {
+    if secs == 0 {
+        return Err("Update interval must be greater than 0".to_string());
+    }
+    config::update_config(|config| config.update_interval_secs = secs);
+    schedule_certify_timer();
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_update_interval() -> u64 {
+    config::with_config(|config| config.update_interval_secs)
+}
+
+// Re-certifies everything right away and returns the new root hash
+#[ic_cdk::update(guard = "is_controller")]
+async fn certify_now() -> Result<String, String> {
+    certify(false).await?;
+    Ok(hex::encode(asset::root_hash()))
+}
+
+// The call context counter (type 1) accumulates across awaits, but saturate anyway so a
+// counter reset can never trap the timer.
+fn instructions_used(start: u64, end: u64) -> u64 {
+    end.saturating_sub(start)
 }
 
 #[ic_cdk::query]
 fn http_request(req: HttpRequest) -> HttpResponse {
     let _path = req.get_path().expect("Failed to parse request path");
-    asset::serve_asset(&req)
+    // State changes in queries are discarded, so counting needs an update call
+    if config::with_config(|config| config.count_requests) {
+        return HttpResponse::builder().with_upgrade(true).build();
+    }
+
+    serve(&req, ic_cdk::api::data_certificate())
+}
+
+// Served through consensus, so the response doesn't need a data certificate. Every
+// counted request costs an update call, and is also added to the access log. Requests
+// without a certified response, like most ranges or unnormalized URLs, are upgraded even
+// while counting is disabled.
+#[ic_cdk::update]
+fn http_request_update(req: HttpRequest) -> HttpResponse {
+    let counted = config::with_config(|config| config.count_requests);
+    if counted {
+        count_request(&req);
+    }
+    let response = serve(&req, Some(vec![]));
+    if counted {
+        log_request(&req, &response);
+    }
+    response
+}
+
+fn serve(req: &HttpRequest, data_certificate: Option<Vec<u8>>) -> HttpResponse<'static> {
+    // Preflights, rejections and echoed origins can't be certified, so the update call
+    // answers them
+    let update = data_certificate
+        .as_ref()
+        .is_some_and(|certificate| certificate.is_empty());
+    let uncertified = body_too_large(req)
+        || req.method().as_str().eq_ignore_ascii_case("OPTIONS")
+        || cors::echoes_origin(req);
+    if uncertified && !update {
+        return HttpResponse::builder().with_upgrade(true).build();
+    }
+    if body_too_large(req) {
+        return asset::error_response(StatusCode::PAYLOAD_TOO_LARGE, "Payload too large");
+    }
+    if req.method().as_str().eq_ignore_ascii_case("OPTIONS") {
+        return cors::preflight(req);
+    }
+    let response = asset::serve_asset(req, data_certificate);
+    cors::with_cors_headers(req, response)
 }
 
-async fn serve_canister_info<'a>(file: &File<'a>) -> String {
+fn body_too_large(req: &HttpRequest) -> bool {
+    let max_body_bytes = config::with_config(|config| config.max_body_bytes);
+    max_body_bytes > 0
+        && !req.method().as_str().eq_ignore_ascii_case("GET")
+        && req.body().len() as u64 > max_body_bytes
+}
+
+fn count_request(req: &HttpRequest) {
+    let Ok(path) = req.get_path() else {
+        return;
+    };
+    REQUEST_COUNTS.with_borrow_mut(|counts| {
+        let key = if counts.contains_key(&path) || counts.len() < MAX_COUNTED_PATHS {
+            path
+        } else {
+            OTHER_PATHS.to_string()
+        };
+        *counts.entry(key).or_default() += 1;
+    });
+}
+
+fn log_request(req: &HttpRequest, response: &HttpResponse<'static>) {
+    let entry = RequestLogEntry {
+        path: req.get_path().unwrap_or_default(),
+        status_code: response.status_code().as_u16(),
+        timestamp_nanos: ic_cdk::api::time(),
+        encoding: asset::get_header(response.headers(), "content-encoding")
+            .unwrap_or("identity")
+            .to_string(),
+        size: response.body().len() as u64,
+    };
+    ACCESS_LOG.with_borrow_mut(|log| {
+        if log.len() == ACCESS_LOG_CAPACITY {
+            log.pop_front();
+        }
+        log.push_back(entry);
+    });
+}
+
+// Newest first. Only requests served while request counting is enabled are logged, as
+// queries can't keep state.
+#[ic_cdk::query(guard = "is_status_reader")]
+fn recent_requests(limit: u32) -> Vec<RequestLogEntry> {
+    ACCESS_LOG.with_borrow(|log| log.iter().rev().take(limit as usize).cloned().collect())
+}
+
+// Counts since the last upgrade
+#[ic_cdk::query(guard = "is_status_reader")]
+fn request_counts() -> Vec<(String, u64)> {
+    REQUEST_COUNTS.with_borrow(|counts| counts.clone().into_iter().collect())
+}
+
+async fn fetch_canister_status() -> Result<DefiniteCanisterStatus, String> {
     let response = canister_status(CanisterIdRecord {
         canister_id: ic_cdk::id(),
     })
     .await
-    .unwrap()
+    .map_err(|(code, msg)| format!("canister_status failed ({:?}): {}", code, msg))?
     .0;
 
+    let mut definite_response = DefiniteCanisterStatus::from(response);
+    let previous_cycles =
+        CYCLES_HISTORY.with_borrow(|history| history.back().map(|sample| sample.cycles));
+    record_cycles(definite_response.cycles);
+    // Only the page render is throttled, the history is fetched again along with it
+    let cached_history = cached_status(&definite_response).map(|cached| cached.canister_history);
+    let cache_hit = cached_history.is_some();
+    definite_response.canister_history = match cached_history {
+        Some(history) => history,
+        None => {
+            canister_info(CanisterInfoRequest {
+                canister_id: ic_cdk::id(),
+                num_requested_changes: Some(config::with_config(|config| config.history_length)),
+            })
+            .await
+            .map_err(|(code, msg)| format!("canister_info failed ({:?}): {}", code, msg))?
+            .0
+            .recent_changes
+        }
+    };
+
+    definite_response.last_updated_at = timestamp(ic_cdk::api::time());
+    definite_response.last_cycles_cost = LAST_CYCLES_FOR_TIMER.with_borrow(|v| *v);
+    definite_response.cost_breakdown = cost_breakdown();
+    definite_response.status_failures = STATUS_FAILURES.with_borrow(|v| *v);
+    definite_response.cycles_delta =
+        previous_cycles.map(|previous| cycles_delta(previous, definite_response.cycles));
+    definite_response.cycles_history = cycles_history();
+    definite_response.request_counts = request_counts();
+    definite_response.low_cycles_warning = definite_response.cycles
+        < u128::from(config::with_config(|config| config.low_cycles_threshold));
+    definite_response.heap_memory_bytes = heap_memory_bytes();
+    definite_response.stable_memory_bytes = ic_cdk::api::stable::stable_size() * WASM_PAGE_SIZE;
+    // The run currently rendering isn't certified yet, so this reports the previous one
+    if let Some((certified_at, duration_ms)) = LAST_CERTIFICATION.with_borrow(|v| *v) {
+        definite_response.last_certified_at = timestamp(certified_at);
+        definite_response.certification_duration_ms = duration_ms;
+    }
+
+    if !cache_hit {
+        RENDER_CACHE.with_borrow_mut(|cache| {
+            *cache = Some(RenderCache {
+                cached_at: ic_cdk::api::time(),
+                status: definite_response.clone(),
+                rendered: None,
+            })
+        });
+    }
+    LAST_STATUS.set(Some(definite_response.clone()));
+    Ok(definite_response)
+}
+
+// Most recent changes last, capped at the IC maximum. Controllers only, as every call
+// costs cycles.
+#[ic_cdk::update(guard = "is_controller")]
+async fn canister_history(n: u32) -> Result<Vec<CanisterChange>, String> {
     let info = canister_info(CanisterInfoRequest {
         canister_id: ic_cdk::id(),
-        num_requested_changes: Some(20),
+        num_requested_changes: Some(u64::from(n).min(config::MAX_HISTORY_LENGTH)),
     })
     .await
-    .unwrap()
+    .map_err(|(code, msg)| format!("canister_info failed ({:?}): {}", code, msg))?
     .0;
+    Ok(info.recent_changes)
+}
+
+// Set by the build, e.g. GIT_SHA=$(git rev-parse HEAD)
+const GIT_SHA: Option<&str> = option_env!("GIT_SHA");
+const BUILD_TIMESTAMP: Option<&str> = option_env!("BUILD_TIMESTAMP");
+
+// The module hash is the one of the last status snapshot
+#[ic_cdk::query(guard = "is_status_reader")]
+fn build_info() -> BuildInfo {
+    let module_hash =
+        LAST_STATUS.with_borrow(|status| status.as_ref().map(|status| status.module_hash.clone()));
+    build_info_with(module_hash)
+}
+
+fn build_info_with(module_hash: Option<String>) -> BuildInfo {
+    BuildInfo {
+        git_sha: GIT_SHA.unwrap_or("unknown").to_string(),
+        build_timestamp: BUILD_TIMESTAMP.unwrap_or("unknown").to_string(),
+        module_hash: module_hash.unwrap_or_else(|| "unknown".to_string()),
+    }
+}
+
+// The snapshot the certified assets were last rendered from
+#[ic_cdk::query(guard = "is_status_reader")]
+fn metrics() -> Option<DefiniteCanisterStatus> {
+    LAST_STATUS.with_borrow(|status| status.clone())
+}
+
+// Oldest first, one sample per status fetch
+#[ic_cdk::query(guard = "is_status_reader")]
+fn cycles_history() -> Vec<CyclesSample> {
+    CYCLES_HISTORY.with_borrow(|history| history.iter().cloned().collect())
+}
+
+#[ic_cdk::query(guard = "is_status_reader")]
+fn last_timer_cost() -> u64 {
+    LAST_CYCLES_FOR_TIMER.with_borrow(|v| *v)
+}
+
+// Over the last TIMER_COSTS_CAPACITY runs
+#[ic_cdk::query(guard = "is_status_reader")]
+fn timer_cost_stats() -> TimerCostStats {
+    TIMER_COSTS.with_borrow(|costs| TimerCostStats {
+        last: costs.back().copied().unwrap_or(0),
+        average: costs.iter().sum::<u64>() / (costs.len() as u64).max(1),
+        max: costs.iter().copied().max().unwrap_or(0),
+        runs: costs.len() as u32,
+    })
+}
+
+#[ic_cdk::query(guard = "is_status_reader")]
+fn cost_breakdown() -> CostBreakdown {
+    COST_BREAKDOWN.with_borrow(|breakdown| breakdown.clone())
+}
+
+pub(crate) fn record_phase_costs(status_calls: u64, rendering: u64) {
+    PHASE_COSTS.set((status_calls, rendering));
+}
+
+fn record_timer_cost(cost: u64) {
+    TIMER_COSTS.with_borrow_mut(|costs| {
+        if costs.len() == TIMER_COSTS_CAPACITY {
+            costs.pop_front();
+        }
+        costs.push_back(cost);
+    });
+}
+
+fn record_cycles(cycles: u128) {
+    CYCLES_HISTORY.with_borrow_mut(|history| {
+        if history.len() == CYCLES_HISTORY_CAPACITY {
+            history.pop_front();
+        }
+        history.push_back(CyclesSample {
+            timestamp_nanos: ic_cdk::api::time(),
+            cycles,
+        });
+    });
+}
+
+// Diagnostics for correlating gateway verification failures with the certified state
+#[ic_cdk::query]
+fn certified_root_hash() -> String {
+    hex::encode(asset::root_hash())
+}
+
+#[ic_cdk::query]
+fn certified_asset_count() -> u64 {
+    asset::certified_asset_count() as u64
+}
+
+#[ic_cdk::query]
+fn list_assets() -> Vec<(String, u64)> {
+    asset::list_assets()
+}
+
+// Skips content negotiation, for tooling verifying the bodies against
+// dump_certification_tree. Larger assets have to be fetched with range requests.
+#[ic_cdk::query(guard = "is_status_reader")]
+fn get_asset_bytes(path: String) -> Result<Option<Vec<u8>>, String> {
+    match asset::asset_bytes(&path) {
+        Some(bytes) if bytes.len() > MAX_ASSET_BYTES_REPLY => Err(format!(
+            "{} is {} bytes, more than the {} that fit in a reply, use range requests instead",
+            path,
+            bytes.len(),
+            MAX_ASSET_BYTES_REPLY
+        )),
+        bytes => Ok(bytes),
+    }
+}
+
+// Compare the hashes with the response a gateway rejected, and certified_root_hash with
+// the certified data in the certificate it used
+#[ic_cdk::query(guard = "is_controller")]
+fn dump_certification_tree() -> Vec<(String, String, String)> {
+    asset::dump_certification_tree()
+}
+
+fn cached_status(status: &DefiniteCanisterStatus) -> Option<DefiniteCanisterStatus> {
+    let now = ic_cdk::api::time();
+    RENDER_CACHE.with_borrow(|cache| {
+        cache
+            .as_ref()
+            .filter(|cache| {
+                cache.status.module_hash == status.module_hash
+                    && cache.status.controllers == status.controllers
+                    && now.saturating_sub(cache.cached_at)
+                        < Duration::from_secs(RENDER_CACHE_TTL_SECS).as_nanos() as u64
+            })
+            .map(|cache| cache.status.clone())
+    })
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn force_refresh() {
+    invalidate_render_cache();
+    certify_helper(true);
+}
+
+fn invalidate_render_cache() {
+    RENDER_CACHE.with_borrow_mut(|cache| *cache = None);
+}
+
+// Every template is registered under its name, so templates can include each other as
+// partials. Returns the rendered output of each template by name.
+fn serve_canister_info(
+    templates: &[(String, &File<'_>)],
+    partials: &[(String, &File<'_>)],
+    status: &DefiniteCanisterStatus,
+) -> Vec<(String, String)> {
+    let cached =
+        RENDER_CACHE.with_borrow(|cache| cache.as_ref().and_then(|cache| cache.rendered.clone()));
+    if let Some(rendered) = cached {
+        return rendered;
+    }
 
     let mut handlebars = Handlebars::new();
-    let source = file.contents();
-    assert!(handlebars
-        .register_template_string("metrics", String::from_utf8_lossy(source))
-        .is_ok());
-    handlebars.register_helper("toJSON", Box::new(toJSON));
-    handlebars.register_helper("toNumLocale", Box::new(toNumLocale));
+    for (name, file) in templates {
+        let source = String::from_utf8_lossy(file.contents());
+        if let Err(err) = handlebars.register_template_string(name, source) {
+            log(&format!("Failed to register template {}: {}", name, err));
+        }
+    }
+    for (name, file) in partials {
+        let source = String::from_utf8_lossy(file.contents());
+        if let Err(err) = handlebars.register_partial(name, source) {
+            log(&format!("Failed to register partial {}: {}", name, err));
+        }
+    }
+    helpers::register_helpers(&mut handlebars);
 
-    let mut definite_response = DefiniteCanisterStatus::from(response);
-    definite_response.last_updated_at = timestamp(ic_cdk::api::time());
-    definite_response.canister_history = info.recent_changes;
-    definite_response.last_cycles_cost = LAST_CYCLES_FOR_TIMER.with_borrow(|v| *v);
-    handlebars.render("metrics", &definite_response).unwrap()
+    let rendered: Vec<(String, String)> = templates
+        .iter()
+        .map(|(name, file)| {
+            // A broken template is served as is rather than taking down certification
+            let rendered = handlebars.render(name, status).unwrap_or_else(|err| {
+                log(&format!("Failed to render template {}: {}", name, err));
+                String::from_utf8_lossy(file.contents()).into_owned()
+            });
+            (name.clone(), rendered)
+        })
+        .collect();
+    RENDER_CACHE.with_borrow_mut(|cache| {
+        if let Some(cache) = cache {
+            cache.rendered = Some(rendered.clone());
+        }
+    });
+    rendered
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 struct DefiniteCanisterStatus {
     pub status: CanisterStatusType,
     pub module_hash: String,
     pub memory_size: u64,
-    pub cycles: u64,
+    #[serde(serialize_with = "serialize_cycles")]
+    pub cycles: u128,
     pub idle_cycles_burned_per_day: u64,
     pub reserved_cycles: u64,
 
@@ -115,6 +678,7 @@ struct DefiniteCanisterStatus {
     pub query_request_payload_bytes_total: u64,
     pub query_response_payload_bytes_total: u64,
 
+    // Principal serializes as its textual form in human readable formats (JSON, handlebars)
     pub controllers: Vec<Principal>,
     pub compute_allocation: u64,
     pub memory_allocation: u64,
@@ -126,15 +690,72 @@ struct DefiniteCanisterStatus {
     pub last_updated_at: String,
     pub canister_history: Vec<CanisterChange>,
     pub last_cycles_cost: u64,
+    pub last_certified_at: String,
+    pub certification_duration_ms: u64,
+    pub cycles_history: Vec<CyclesSample>,
+    pub request_counts: Vec<(String, u64)>,
+    pub estimated_days_until_frozen: Option<u64>,
+    pub low_runway_warning: bool,
+    pub low_cycles_warning: bool,
+    // Of this canister only, memory_size also includes its code and metadata
+    pub heap_memory_bytes: u64,
+    pub stable_memory_bytes: u64,
+    pub cost_breakdown: CostBreakdown,
+    pub canister_id: String,
+    // Canisters have no system API for their subnet, always None until there is one
+    pub subnet_id: Option<String>,
+    // Failures of the runs before this one, as a failing run renders nothing
+    pub status_failures: u32,
+    // Since the previous status fetch, None on the first one
+    pub cycles_delta: Option<i64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct BuildInfo {
+    pub git_sha: String,
+    pub build_timestamp: String,
+    pub module_hash: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct RequestLogEntry {
+    pub path: String,
+    pub status_code: u16,
+    pub timestamp_nanos: u64,
+    pub encoding: String,
+    pub size: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct CyclesSample {
+    pub timestamp_nanos: u64,
+    #[serde(serialize_with = "serialize_cycles")]
+    pub cycles: u128,
+}
+
+// Cycles spent in each phase of the last certification run
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct CostBreakdown {
+    pub status_calls: u64,
+    pub rendering: u64,
+    pub certification: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TimerCostStats {
+    pub last: u64,
+    pub average: u64,
+    pub max: u64,
+    pub runs: u32,
 }
 
 impl From<CanisterStatusResponse> for DefiniteCanisterStatus {
     fn from(value: CanisterStatusResponse) -> Self {
-        Self {
+        let mut status = Self {
             status: value.status,
             module_hash: hex::encode(value.module_hash.expect("Wasm should exist")),
             memory_size: nu64(value.memory_size),
-            cycles: nu64(value.cycles),
+            cycles: nu128(value.cycles),
             idle_cycles_burned_per_day: nu64(value.idle_cycles_burned_per_day),
             reserved_cycles: nu64(value.reserved_cycles),
 
@@ -156,16 +777,368 @@ impl From<CanisterStatusResponse> for DefiniteCanisterStatus {
             last_updated_at: String::new(),
             canister_history: vec![],
             last_cycles_cost: 0,
-        }
+            last_certified_at: String::new(),
+            certification_duration_ms: 0,
+            cycles_history: vec![],
+            request_counts: vec![],
+            estimated_days_until_frozen: None,
+            low_runway_warning: false,
+            low_cycles_warning: false,
+            heap_memory_bytes: 0,
+            stable_memory_bytes: 0,
+            cost_breakdown: CostBreakdown::default(),
+            canister_id: ic_cdk::id().to_text(),
+            subnet_id: None,
+            status_failures: 0,
+            cycles_delta: None,
+        };
+        status.estimated_days_until_frozen = days_until_frozen(
+            status.cycles,
+            status.idle_cycles_burned_per_day,
+            status.freezing_threshold,
+        );
+        status.low_runway_warning = status
+            .estimated_days_until_frozen
+            .is_some_and(|days| days < LOW_RUNWAY_DAYS);
+        status
+    }
+}
+
+// The canister freezes once its balance can't cover the freezing threshold (in seconds)
+// of idle burn. No burn means it never freezes.
+fn days_until_frozen(
+    cycles: u128,
+    burned_per_day: u64,
+    freezing_threshold_secs: u64,
+) -> Option<u64> {
+    if burned_per_day == 0 {
+        return None;
+    }
+    let burned_per_day = u128::from(burned_per_day);
+    let reserve = burned_per_day * u128::from(freezing_threshold_secs) / 86_400;
+    let days = cycles.saturating_sub(reserve) / burned_per_day;
+    Some(u64::try_from(days).unwrap_or(u64::MAX))
+}
+
+// Outside a canister, e.g. in unit tests, the system clock stands in for the IC's
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn now() -> u64 {
+    ic_cdk::api::time()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_nanos() as u64)
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn set_certified_data(data: &[u8]) {
+    ic_cdk::api::set_certified_data(data);
+}
+
+// Nothing to certify it with outside a canister
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn set_certified_data(_data: &[u8]) {}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn log(message: &str) {
+    ic_cdk::println!("{}", message);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn log(message: &str) {
+    eprintln!("{}", message);
+}
+
+#[cfg(target_arch = "wasm32")]
+fn heap_memory_bytes() -> u64 {
+    core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn heap_memory_bytes() -> u64 {
+    0
+}
+
+fn cycles_delta(previous: u128, current: u128) -> i64 {
+    if current >= previous {
+        i64::try_from(current - previous).unwrap_or(i64::MAX)
+    } else {
+        i64::try_from(previous - current).map_or(i64::MIN, |delta| -delta)
     }
 }
 
+// Saturates instead of trapping on values the management canister may legitimately return
 fn nu64(num: Nat) -> u64 {
-    num.0.to_u64().expect("Nat doesn't fit into u64")
+    num.0.to_u64().unwrap_or(u64::MAX)
+}
+
+fn nu128(num: Nat) -> u128 {
+    num.0.to_u128().unwrap_or(u128::MAX)
 }
 
+// Templates are rendered from serde_json values, which can't hold integers above u64,
+// so larger balances saturate like nu64 does
+fn serialize_cycles<S: serde::Serializer>(cycles: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(u64::try_from(*cycles).unwrap_or(u64::MAX))
+}
+
+// In the configured display offset, suffixed with the zone, e.g. "UTC" or "+05:30"
 fn timestamp(time: u64) -> String {
     let timestamp = time as i64;
     let datetime = DateTime::from_timestamp_nanos(timestamp);
-    datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+    let offset_secs = config::with_config(|config| config.display_offset_secs);
+    match FixedOffset::east_opt(offset_secs) {
+        Some(offset) if offset_secs != 0 => datetime
+            .with_timezone(&offset)
+            .format("%Y-%m-%d %H:%M:%S %:z")
+            .to_string(),
+        _ => datetime.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl DefiniteCanisterStatus {
+        // A running canister with nothing in its history, for rendering templates against
+        pub(crate) fn fixture() -> Self {
+            Self {
+                status: CanisterStatusType::Running,
+                module_hash: "ab".repeat(32),
+                memory_size: 0,
+                cycles: 1_000_000_000_000,
+                idle_cycles_burned_per_day: 0,
+                reserved_cycles: 0,
+                query_num_calls_total: 0,
+                query_num_instructions_total: 0,
+                query_request_payload_bytes_total: 0,
+                query_response_payload_bytes_total: 0,
+                controllers: vec![],
+                compute_allocation: 0,
+                memory_allocation: 0,
+                freezing_threshold: 2_592_000,
+                reserved_cycles_limit: 0,
+                log_visibility: LogVisibility::Controllers,
+                wasm_memory_limit: 0,
+                last_updated_at: String::new(),
+                canister_history: vec![],
+                last_cycles_cost: 0,
+                last_certified_at: String::new(),
+                certification_duration_ms: 0,
+                cycles_history: vec![],
+                request_counts: vec![],
+                estimated_days_until_frozen: None,
+                low_runway_warning: false,
+                low_cycles_warning: false,
+                heap_memory_bytes: 0,
+                stable_memory_bytes: 0,
+                cost_breakdown: CostBreakdown::default(),
+                canister_id: "ryjl3-tyaaa-aaaaa-aaaba-cai".to_string(),
+                subnet_id: None,
+                status_failures: 0,
+                cycles_delta: None,
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_bodies_get_a_413_from_the_update_call() {
+        config::update_config(|config| config.max_body_bytes = 4);
+        let req = HttpRequest::post("/index.html")
+            .with_body(b"too large".to_vec())
+            .build();
+        assert_eq!(serve(&req, Some(vec![1, 2, 3])).upgrade(), Some(true));
+        let response = serve(&req, Some(vec![]));
+        assert_eq!(response.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(response.body(), b"Payload too large");
+    }
+
+    #[test]
+    fn echoed_origins_and_preflights_get_answered_by_the_update_call() {
+        config::update_config(|config| {
+            config.cors_allowed_origins = vec![
+                "https://a.example".to_string(),
+                "https://b.example".to_string(),
+            ];
+        });
+        let origin = ("origin".to_string(), "https://b.example".to_string());
+        let req = HttpRequest::get("/index.html")
+            .with_headers(vec![origin.clone()])
+            .build();
+        assert_eq!(serve(&req, Some(vec![1, 2, 3])).upgrade(), Some(true));
+
+        let preflight = HttpRequest::builder()
+            .with_method(ic_http_certification::Method::OPTIONS)
+            .with_url("/index.html")
+            .with_headers(vec![origin])
+            .build();
+        assert_eq!(serve(&preflight, Some(vec![1, 2, 3])).upgrade(), Some(true));
+        let response = serve(&preflight, Some(vec![]));
+        assert_eq!(response.status_code(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            asset::get_header(response.headers(), "access-control-allow-origin"),
+            Some("https://b.example")
+        );
+    }
+
+    #[test]
+    fn a_running_certification_blocks_another_until_it_times_out() {
+        let timeout = CERTIFY_TIMEOUT_SECS * 1_000_000_000;
+        assert!(try_start_certification(1_000));
+        assert!(!try_start_certification(1_000));
+        assert!(!try_start_certification(1_000 + timeout - 1));
+        // A run that trapped never clears the flag
+        assert!(try_start_certification(1_000 + timeout));
+
+        CERTIFYING.set(None);
+        assert!(try_start_certification(2_000));
+    }
+
+    #[test]
+    fn malformed_templates_are_served_as_is() {
+        let broken = File::new("broken.hbs", b"<p>{{#if module_hash}}unclosed</p>");
+        let index = File::new("index.hbs", b"<p>{{canister_id}}</p>");
+        let templates = [
+            ("broken".to_string(), &broken),
+            ("index".to_string(), &index),
+        ];
+
+        let rendered = serve_canister_info(&templates, &[], &DefiniteCanisterStatus::fixture());
+        assert_eq!(
+            rendered,
+            vec![
+                (
+                    "broken".to_string(),
+                    "<p>{{#if module_hash}}unclosed</p>".to_string()
+                ),
+                (
+                    "index".to_string(),
+                    "<p>ryjl3-tyaaa-aaaaa-aaaba-cai</p>".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn nats_above_u64_saturate() {
+        let above_u64 = Nat::from(u128::from(u64::MAX) + 1);
+        assert_eq!(nu64(above_u64.clone()), u64::MAX);
+        assert_eq!(nu128(above_u64), u128::from(u64::MAX) + 1);
+        assert_eq!(nu64(Nat::from(42u64)), 42);
+        assert_eq!(nu128(Nat::from(u128::MAX) + Nat::from(1u8)), u128::MAX);
+    }
+
+    #[test]
+    fn a_decreasing_counter_uses_no_instructions() {
+        assert_eq!(instructions_used(1_000, 1_500), 500);
+        assert_eq!(instructions_used(1_500, 1_000), 0);
+        assert_eq!(instructions_used(u64::MAX, 0), 0);
+    }
+
+    #[test]
+    fn cycles_above_u64_serialize_saturated() {
+        let serialize = |cycles| serialize_cycles(&cycles, serde_json::value::Serializer).unwrap();
+        assert_eq!(serialize(42), serde_json::json!(42));
+        assert_eq!(serialize(u128::from(u64::MAX)), serde_json::json!(u64::MAX));
+        assert_eq!(
+            serialize(u128::from(u64::MAX) + 1),
+            serde_json::json!(u64::MAX)
+        );
+        assert_eq!(serialize(u128::MAX), serde_json::json!(u64::MAX));
+    }
+
+    #[test]
+    fn days_until_frozen_without_burn_is_never() {
+        assert_eq!(days_until_frozen(1_000_000_000_000, 0, 2_592_000), None);
+        assert_eq!(days_until_frozen(0, 0, 0), None);
+    }
+
+    #[test]
+    fn days_until_frozen_keeps_the_freezing_reserve() {
+        // 30 days of burn are held back by the default freezing threshold
+        assert_eq!(days_until_frozen(100_000, 1_000, 2_592_000), Some(70));
+        assert_eq!(days_until_frozen(100_000, 1_000, 0), Some(100));
+        // High burn, the balance doesn't even cover the reserve
+        assert_eq!(days_until_frozen(100_000, 50_000, 2_592_000), Some(0));
+        assert_eq!(days_until_frozen(u128::MAX, 1, 0), Some(u64::MAX));
+    }
+
+    #[test]
+    fn cycles_delta_is_signed() {
+        assert_eq!(cycles_delta(1_000, 1_500), 500);
+        assert_eq!(cycles_delta(1_500, 1_000), -500);
+        assert_eq!(cycles_delta(1_000, 1_000), 0);
+        assert_eq!(cycles_delta(0, u128::MAX), i64::MAX);
+        assert_eq!(cycles_delta(u128::MAX, 0), i64::MIN);
+    }
+
+    fn set_display_offset(offset_secs: i32) {
+        let mut config = config::snapshot();
+        config.display_offset_secs = offset_secs;
+        config::replace(config);
+    }
+
+    #[test]
+    fn timestamps_default_to_utc() {
+        assert_eq!(timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(
+            timestamp(1_719_835_200_000_000_000),
+            "2024-07-01 12:00:00 UTC"
+        );
+    }
+
+    #[test]
+    fn timestamps_use_the_display_offset_all_year() {
+        set_display_offset(5 * 3_600 + 30 * 60);
+        assert_eq!(timestamp(0), "1970-01-01 05:30:00 +05:30");
+        // A fixed offset, the same in winter and summer
+        set_display_offset(-5 * 3_600);
+        assert_eq!(
+            timestamp(1_704_110_400_000_000_000),
+            "2024-01-01 07:00:00 -05:00"
+        );
+        assert_eq!(
+            timestamp(1_719_835_200_000_000_000),
+            "2024-07-01 07:00:00 -05:00"
+        );
+    }
+
+    // Runs timer ticks until one would fetch the status again
+    fn skipped_ticks() -> u32 {
+        let mut skipped = 0;
+        while skip_backoff_tick() {
+            skipped += 1;
+        }
+        skipped
+    }
+
+    #[test]
+    fn consecutive_failures_skip_more_ticks() {
+        let failed = Err("canister_status failed".to_string());
+        let skipped: Vec<u32> = (0..7)
+            .map(|_| {
+                record_status_outcome(&failed);
+                skipped_ticks()
+            })
+            .collect();
+        assert_eq!(skipped, vec![1, 3, 7, 15, 31, 31, 31]);
+        assert_eq!(STATUS_FAILURES.with_borrow(|failures| *failures), 7);
+
+        record_status_outcome(&Ok(()));
+        assert_eq!(skipped_ticks(), 0);
+        assert_eq!(STATUS_FAILURES.with_borrow(|failures| *failures), 0);
+    }
+
+    #[test]
+    fn backoff_ticks_are_capped() {
+        assert_eq!(backoff_ticks(0), 0);
+        assert_eq!(backoff_ticks(1), 1);
+        assert_eq!(backoff_ticks(5), MAX_BACKOFF_TICKS);
+        assert_eq!(backoff_ticks(32), MAX_BACKOFF_TICKS);
+        assert_eq!(backoff_ticks(u32::MAX), MAX_BACKOFF_TICKS);
+    }
 }
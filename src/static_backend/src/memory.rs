@@ -0,0 +1,22 @@
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::DefaultMemoryImpl;
+use std::cell::RefCell;
+
+pub(crate) type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Ids can't be reused once they held data, new state gets the next free one
+const CONFIG_MEMORY_ID: MemoryId = MemoryId::new(0);
+const UPLOADS_MEMORY_ID: MemoryId = MemoryId::new(1);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub(crate) fn config_memory() -> Memory {
+    MEMORY_MANAGER.with_borrow(|manager| manager.get(CONFIG_MEMORY_ID))
+}
+
+pub(crate) fn uploads_memory() -> Memory {
+    MEMORY_MANAGER.with_borrow(|manager| manager.get(UPLOADS_MEMORY_ID))
+}
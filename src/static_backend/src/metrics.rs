@@ -0,0 +1,67 @@
+use crate::DefiniteCanisterStatus;
+use std::fmt::Write;
+
+// Prometheus text exposition format, see
+// https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format
+pub(crate) fn render_metrics(status: &DefiniteCanisterStatus) -> String {
+    let metrics: [(&str, &str, u128); 11] = [
+        ("canister_cycles", "gauge", status.cycles),
+        (
+            "canister_memory_size_bytes",
+            "gauge",
+            status.memory_size.into(),
+        ),
+        (
+            "canister_idle_cycles_burned_per_day",
+            "gauge",
+            status.idle_cycles_burned_per_day.into(),
+        ),
+        (
+            "canister_reserved_cycles",
+            "gauge",
+            status.reserved_cycles.into(),
+        ),
+        (
+            "canister_query_num_calls_total",
+            "counter",
+            status.query_num_calls_total.into(),
+        ),
+        (
+            "canister_query_num_instructions_total",
+            "counter",
+            status.query_num_instructions_total.into(),
+        ),
+        (
+            "canister_query_request_payload_bytes_total",
+            "counter",
+            status.query_request_payload_bytes_total.into(),
+        ),
+        (
+            "canister_query_response_payload_bytes_total",
+            "counter",
+            status.query_response_payload_bytes_total.into(),
+        ),
+        (
+            "canister_last_cycles_cost",
+            "gauge",
+            status.last_cycles_cost.into(),
+        ),
+        (
+            "canister_certification_duration_ms",
+            "gauge",
+            status.certification_duration_ms.into(),
+        ),
+        (
+            "canister_low_cycles_warning",
+            "gauge",
+            status.low_cycles_warning.into(),
+        ),
+    ];
+
+    let mut out = String::new();
+    for (name, kind, value) in metrics {
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{}{{}} {}", name, value);
+    }
+    out
+}
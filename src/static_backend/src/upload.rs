@@ -0,0 +1,387 @@
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::config::is_controller;
+use crate::memory::{self, Memory};
+
+// Incomplete uploads are dropped after this long
+const UPLOAD_TIMEOUT_SECS: u64 = 3_600;
+
+thread_local! {
+    // In stable memory, so upgrades don't have to copy them
+    static UPLOADED_ASSETS: RefCell<UploadedAssets> =
+        RefCell::new(StableBTreeMap::init(memory::uploads_memory()));
+    // Not kept across upgrades, an interrupted upload has to be started again
+    static PENDING_UPLOADS: RefCell<BTreeMap<u64, PendingUpload>> = RefCell::default();
+    static NEXT_UPLOAD_ID: RefCell<u64> = const { RefCell::new(0) };
+}
+
+// Keyed by path without the leading slash
+pub(crate) type UploadedAssets = StableBTreeMap<String, UploadedAsset, Memory>;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub(crate) struct UploadedAsset {
+    pub content_type: String,
+    pub content: Vec<u8>,
+    // Matches the certified ETag, which is the hex encoded SHA-256 of the content
+    pub sha256: [u8; 32],
+    // Nanoseconds since the epoch, served as Last-Modified
+    pub uploaded_at: u64,
+}
+
+impl Storable for UploadedAsset {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap_or_else(|err| {
+            ic_cdk::trap(&format!("Failed to encode uploaded asset: {}", err))
+        }))
+    }
+
+    // Trapping keeps an upgrade that can't read the uploads from dropping them
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|err| {
+            ic_cdk::trap(&format!("Failed to decode uploaded asset: {}", err))
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+struct PendingUpload {
+    path: String,
+    content_type: String,
+    total_len: u64,
+    // Keyed by offset, a chunk sent again for the same offset replaces the previous one
+    chunks: BTreeMap<u64, Vec<u8>>,
+    created_at: u64,
+}
+
+pub(crate) fn with_uploaded_assets<R>(f: impl FnOnce(&UploadedAssets) -> R) -> R {
+    UPLOADED_ASSETS.with_borrow(f)
+}
+
+// Replaces any bundled or uploaded asset at the same path. Files larger than a single
+// message go through create_upload, put_chunk and commit_upload instead.
+#[ic_cdk::update(guard = "is_controller")]
+fn store_asset(path: String, content: Vec<u8>, content_type: String) -> Result<(), String> {
+    let path = normalize_path(&path)?;
+    if content_type.trim().is_empty() {
+        return Err("Content type must not be empty".to_string());
+    }
+    insert_uploaded_asset(path, content, &content_type, ic_cdk::api::time());
+    crate::certify_helper(false);
+    Ok(())
+}
+
+pub(crate) fn insert_uploaded_asset(
+    path: String,
+    content: Vec<u8>,
+    content_type: &str,
+    uploaded_at: u64,
+) {
+    UPLOADED_ASSETS.with_borrow_mut(|assets| {
+        assets.insert(
+            path,
+            UploadedAsset {
+                content_type: content_type.to_string(),
+                sha256: Sha256::digest(&content).into(),
+                content,
+                uploaded_at,
+            },
+        );
+    });
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn create_upload(path: String, total_len: u64, content_type: String) -> Result<u64, String> {
+    let path = normalize_path(&path)?;
+    if content_type.trim().is_empty() {
+        return Err("Content type must not be empty".to_string());
+    }
+    let now = ic_cdk::api::time();
+    expire_uploads(now);
+
+    let upload_id = NEXT_UPLOAD_ID.with_borrow_mut(|id| {
+        *id += 1;
+        *id
+    });
+    PENDING_UPLOADS.with_borrow_mut(|uploads| {
+        uploads.insert(
+            upload_id,
+            PendingUpload {
+                path,
+                content_type,
+                total_len,
+                chunks: BTreeMap::new(),
+                created_at: now,
+            },
+        );
+    });
+    Ok(upload_id)
+}
+
+// Chunks can arrive in any order
+#[ic_cdk::update(guard = "is_controller")]
+fn put_chunk(upload_id: u64, offset: u64, bytes: Vec<u8>) -> Result<(), String> {
+    expire_uploads(ic_cdk::api::time());
+    PENDING_UPLOADS.with_borrow_mut(|uploads| {
+        let upload = uploads
+            .get_mut(&upload_id)
+            .ok_or_else(|| format!("Unknown or expired upload {}", upload_id))?;
+        let end = offset.saturating_add(bytes.len() as u64);
+        if end > upload.total_len {
+            return Err(format!(
+                "Chunk at offset {} ends past the total length {}",
+                offset, upload.total_len
+            ));
+        }
+        upload.chunks.insert(offset, bytes);
+        Ok(())
+    })
+}
+
+// Rejects content that doesn't hash to the expected SHA-256, e.g. a corrupted chunk
+#[ic_cdk::update(guard = "is_controller")]
+fn commit_upload(upload_id: u64, sha256: [u8; 32]) -> Result<(), String> {
+    let upload = PENDING_UPLOADS
+        .with_borrow_mut(|uploads| uploads.remove(&upload_id))
+        .ok_or_else(|| format!("Unknown or expired upload {}", upload_id))?;
+    let content = match assemble(&upload).and_then(|content| verify(content, &sha256)) {
+        Ok(content) => content,
+        Err(err) => {
+            // Keep the upload so the missing chunks can still be sent
+            PENDING_UPLOADS.with_borrow_mut(|uploads| {
+                uploads.insert(upload_id, upload);
+            });
+            return Err(err);
+        }
+    };
+
+    insert_uploaded_asset(
+        upload.path,
+        content,
+        &upload.content_type,
+        ic_cdk::api::time(),
+    );
+    crate::certify_helper(false);
+    Ok(())
+}
+
+// Chunks have to cover the content exactly, without gaps or overlaps
+fn assemble(upload: &PendingUpload) -> Result<Vec<u8>, String> {
+    let mut content = Vec::new();
+    for (offset, chunk) in &upload.chunks {
+        let len = content.len() as u64;
+        if *offset > len {
+            return Err(format!("Missing bytes {}..{}", len, offset));
+        }
+        if *offset < len {
+            return Err(format!(
+                "Chunk at offset {} overlaps the previous one",
+                offset
+            ));
+        }
+        content.extend_from_slice(chunk);
+    }
+    if content.len() as u64 != upload.total_len {
+        return Err(format!(
+            "Missing bytes {}..{}",
+            content.len(),
+            upload.total_len
+        ));
+    }
+    Ok(content)
+}
+
+fn verify(content: Vec<u8>, expected: &[u8; 32]) -> Result<Vec<u8>, String> {
+    let computed: [u8; 32] = Sha256::digest(&content).into();
+    if computed != *expected {
+        return Err(format!(
+            "Content hashes to {}, expected {}",
+            hex::encode(computed),
+            hex::encode(expected)
+        ));
+    }
+    Ok(content)
+}
+
+fn expire_uploads(now: u64) {
+    let timeout = UPLOAD_TIMEOUT_SECS * 1_000_000_000;
+    PENDING_UPLOADS.with_borrow_mut(|uploads| {
+        uploads.retain(|_, upload| now.saturating_sub(upload.created_at) < timeout)
+    });
+}
+
+// Bundled assets at the same path are served again once the upload is deleted
+#[ic_cdk::update(guard = "is_controller")]
+fn delete_asset(path: String) -> Result<(), String> {
+    let path = normalize_path(&path)?;
+    if UPLOADED_ASSETS
+        .with_borrow_mut(|assets| assets.remove(&path))
+        .is_none()
+    {
+        return Err(format!("No uploaded asset at {}", path));
+    }
+    crate::certify_helper(false);
+    Ok(())
+}
+
+// Drops every upload, including unfinished ones, so only the bundled assets are served.
+// The rendered status page is kept. Returns the number of uploaded assets removed.
+#[ic_cdk::update(guard = "is_controller")]
+fn clear_uploaded_assets() -> u64 {
+    let removed = clear_uploads();
+    if removed > 0 {
+        crate::certify_helper(false);
+    }
+    removed
+}
+
+pub(crate) fn clear_uploads() -> u64 {
+    PENDING_UPLOADS.take();
+    UPLOADED_ASSETS.with_borrow_mut(|assets| {
+        let removed = assets.len();
+        assets.clear_new();
+        removed
+    })
+}
+
+// (path, content type, size in bytes, hex encoded SHA-256)
+#[ic_cdk::query]
+fn list_uploaded_assets() -> Vec<(String, String, u64, String)> {
+    with_uploaded_assets(|assets| {
+        assets
+            .iter()
+            .map(|(path, asset)| {
+                (
+                    path,
+                    asset.content_type,
+                    asset.content.len() as u64,
+                    hex::encode(asset.sha256),
+                )
+            })
+            .collect()
+    })
+}
+
+fn normalize_path(path: &str) -> Result<String, String> {
+    let path = path.trim().trim_start_matches('/');
+    if path.is_empty() || path.ends_with('/') {
+        return Err(format!("Invalid asset path {:?}", path));
+    }
+    Ok(path.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload(total_len: u64, chunks: &[(u64, &[u8])]) -> PendingUpload {
+        PendingUpload {
+            path: "app.js".to_string(),
+            content_type: "text/javascript".to_string(),
+            total_len,
+            chunks: chunks
+                .iter()
+                .map(|(offset, bytes)| (*offset, bytes.to_vec()))
+                .collect(),
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn chunks_assemble_in_offset_order() {
+        let upload = upload(9, &[(6, b"ghi"), (0, b"abc"), (3, b"def")]);
+        assert_eq!(assemble(&upload), Ok(b"abcdefghi".to_vec()));
+        assert_eq!(assemble(&self::upload(0, &[])), Ok(vec![]));
+    }
+
+    #[test]
+    fn chunks_with_gaps_overlaps_or_short_content_dont_assemble() {
+        assert_eq!(
+            assemble(&upload(6, &[(0, b"ab"), (4, b"ef")])),
+            Err("Missing bytes 2..4".to_string())
+        );
+        assert_eq!(
+            assemble(&upload(5, &[(0, b"abc"), (2, b"cde")])),
+            Err("Chunk at offset 2 overlaps the previous one".to_string())
+        );
+        assert_eq!(
+            assemble(&upload(8, &[(0, b"abc"), (3, b"def")])),
+            Err("Missing bytes 6..8".to_string())
+        );
+        assert_eq!(
+            assemble(&upload(3, &[(1, b"bc")])),
+            Err("Missing bytes 0..1".to_string())
+        );
+    }
+
+    #[test]
+    fn content_has_to_match_its_hash() {
+        let sha256: [u8; 32] = Sha256::digest(b"content").into();
+        assert_eq!(
+            verify(b"content".to_vec(), &sha256),
+            Ok(b"content".to_vec())
+        );
+        let err = verify(b"corrupted".to_vec(), &sha256).unwrap_err();
+        assert!(
+            err.ends_with(&format!("expected {}", hex::encode(sha256))),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn paths_are_stored_without_the_leading_slash() {
+        assert_eq!(normalize_path("/app.js"), Ok("app.js".to_string()));
+        assert_eq!(
+            normalize_path(" img/logo.png "),
+            Ok("img/logo.png".to_string())
+        );
+        assert_eq!(normalize_path("//app.js"), Ok("app.js".to_string()));
+        assert!(normalize_path("").is_err());
+        assert!(normalize_path("/").is_err());
+        assert!(normalize_path("img/").is_err());
+    }
+
+    #[test]
+    fn uploads_expire_after_the_timeout() {
+        let timeout = UPLOAD_TIMEOUT_SECS * 1_000_000_000;
+        PENDING_UPLOADS.with_borrow_mut(|uploads| {
+            uploads.insert(1, upload(1, &[]));
+            uploads.insert(
+                2,
+                PendingUpload {
+                    created_at: timeout,
+                    ..upload(1, &[])
+                },
+            );
+        });
+        expire_uploads(timeout - 1);
+        assert_eq!(PENDING_UPLOADS.with_borrow(|uploads| uploads.len()), 2);
+        expire_uploads(timeout);
+        let remaining: Vec<u64> =
+            PENDING_UPLOADS.with_borrow(|uploads| uploads.keys().copied().collect());
+        assert_eq!(remaining, vec![2]);
+    }
+
+    #[test]
+    fn clearing_counts_the_removed_uploads() {
+        insert_uploaded_asset("a.txt".to_string(), b"a".to_vec(), "text/plain", 0);
+        insert_uploaded_asset("b.txt".to_string(), b"b".to_vec(), "text/plain", 0);
+        PENDING_UPLOADS.with_borrow_mut(|uploads| {
+            uploads.insert(1, upload(1, &[]));
+        });
+        assert_eq!(list_uploaded_assets().len(), 2);
+
+        assert_eq!(clear_uploads(), 2);
+        assert!(list_uploaded_assets().is_empty());
+        assert!(PENDING_UPLOADS.with_borrow(|uploads| uploads.is_empty()));
+        assert_eq!(clear_uploads(), 0);
+    }
+}
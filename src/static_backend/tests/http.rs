@@ -0,0 +1,173 @@
+use candid::{CandidType, Principal};
+use pocket_ic::{PocketIc, WasmResult};
+use reqwest::blocking::{Client, Response};
+use reqwest::StatusCode;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Built with cargo build --target wasm32-unknown-unknown --release -p static_backend
+const WASM_PATH: &str = "../../target/wasm32-unknown-unknown/release/static_backend.wasm";
+const NOT_FOUND_HTML: &[u8] = include_bytes!("../../assets/404.html");
+const INDEX_JS: &[u8] = include_bytes!("../../assets/index.js");
+
+struct Site {
+    // Also keeps the gateway alive
+    pic: PocketIc,
+    canister_id: Principal,
+    client: Client,
+    base_url: String,
+}
+
+impl Site {
+    // Requests go through the HTTP gateway of PocketIC, which rejects any response that
+    // fails certification, like a boundary node does
+    fn get(&self, path: &str) -> Response {
+        self.client
+            .get(format!("{}{}", self.base_url, path))
+            .send()
+            .unwrap_or_else(|err| panic!("GET {} failed: {}", path, err))
+    }
+
+    // As the anonymous principal, which deploy makes a controller
+    fn update(&self, method: &str, arg: impl CandidType) -> Vec<u8> {
+        let payload = candid::encode_one(arg).unwrap();
+        match self
+            .pic
+            .update_call(self.canister_id, Principal::anonymous(), method, payload)
+        {
+            Ok(WasmResult::Reply(reply)) => reply,
+            result => panic!("{} failed: {:?}", method, result),
+        }
+    }
+
+    fn set_metrics_auth(&self) {
+        let credentials = Some(("admin".to_string(), "secret".to_string()));
+        let reply = self.update("set_metrics_auth", credentials);
+        candid::decode_one::<Result<(), String>>(&reply)
+            .unwrap()
+            .unwrap();
+    }
+
+    // Whether the query replies to a caller that isn't a controller
+    fn query_as_stranger(&self, method: &str, payload: Vec<u8>) -> bool {
+        let stranger = Principal::self_authenticating([1; 32]);
+        matches!(
+            self.pic
+                .query_call(self.canister_id, stranger, method, payload),
+            Ok(WasmResult::Reply(_))
+        )
+    }
+}
+
+fn wasm() -> Vec<u8> {
+    let path = std::env::var_os("STATIC_BACKEND_WASM")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(WASM_PATH));
+    std::fs::read(&path).unwrap_or_else(|err| panic!("Failed to read {:?}: {}", path, err))
+}
+
+// Runs the rounds a timer needs, including the management canister calls it awaits
+fn advance_time(pic: &PocketIc, duration: Duration) {
+    pic.advance_time(duration);
+    for _ in 0..10 {
+        pic.tick();
+    }
+}
+
+fn deploy() -> Site {
+    let mut pic = PocketIc::new();
+    let canister_id = pic.create_canister();
+    pic.add_cycles(canister_id, 2_000_000_000_000);
+    // The status page is rendered from canister_status, which only controllers can call
+    pic.set_controllers(canister_id, None, vec![Principal::anonymous(), canister_id])
+        .unwrap();
+    pic.install_canister(canister_id, wasm(), candid::encode_args(()).unwrap(), None);
+    // The first certification runs on a timer right after install
+    advance_time(&pic, Duration::from_secs(1));
+
+    let gateway = pic.make_live(None);
+    let domain = format!("{}.localhost", canister_id);
+    let addr = SocketAddr::from(([127, 0, 0, 1], gateway.port().unwrap()));
+    let client = Client::builder()
+        .resolve(&domain, addr)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    Site {
+        pic,
+        canister_id,
+        client,
+        base_url: format!("http://{}:{}", domain, addr.port()),
+    }
+}
+
+fn content_type(response: &Response) -> &str {
+    response
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+}
+
+#[test]
+#[ignore = "needs the PocketIC server in POCKET_IC_BIN and the release wasm, see the README"]
+fn serves_certified_assets_end_to_end() {
+    let site = deploy();
+
+    let response = site.get("/");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(content_type(&response), "text/html");
+    assert!(response.text().unwrap().contains("Last updated at"));
+
+    let response = site.get("/index.html");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(content_type(&response), "text/html");
+
+    let response = site.get("/index.js");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(content_type(&response), "text/javascript");
+    assert_eq!(response.bytes().unwrap(), INDEX_JS);
+
+    for path in ["/missing", "/a/b/missing"] {
+        let response = site.get(path);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+        assert_eq!(content_type(&response), "text/html");
+        assert_eq!(response.bytes().unwrap(), NOT_FOUND_HTML);
+    }
+}
+
+#[test]
+#[ignore = "needs the PocketIC server in POCKET_IC_BIN and the release wasm, see the README"]
+fn asset_bytes_of_the_status_page_need_a_controller_behind_auth() {
+    let site = deploy();
+    let path = candid::encode_one("/").unwrap();
+    assert!(site.query_as_stranger("get_asset_bytes", path.clone()));
+
+    site.set_metrics_auth();
+    assert!(!site.query_as_stranger("get_asset_bytes", path));
+}
+
+#[test]
+#[ignore = "needs the PocketIC server in POCKET_IC_BIN and the release wasm, see the README"]
+fn status_queries_need_a_controller_behind_auth() {
+    let site = deploy();
+    let queries = [
+        "metrics",
+        "cycles_history",
+        "build_info",
+        "last_timer_cost",
+        "timer_cost_stats",
+        "cost_breakdown",
+        "status_backoff",
+    ];
+    let no_args = candid::encode_args(()).unwrap();
+    for query in queries {
+        assert!(site.query_as_stranger(query, no_args.clone()), "{}", query);
+    }
+
+    site.set_metrics_auth();
+    for query in queries {
+        assert!(!site.query_as_stranger(query, no_args.clone()), "{}", query);
+    }
+}